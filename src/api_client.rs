@@ -0,0 +1,614 @@
+//! The HTTP client for the OpenAI API.  All requests go through here so
+//! they share authentication, retries and error handling
+use crate::api_key::ApiKey;
+use crate::cache::ResponseCache;
+use crate::cassette::{self, Cassette, Mode};
+use crate::embeddings;
+use crate::profiles::Credentials;
+use crate::usage::Ledger;
+use reqwest::blocking::{multipart::Form, Client, RequestBuilder};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+pub const OPENAI_URL: &str = "https://api.openai.com/v1";
+
+/// How many times to try again after a rate limit, a server error or a
+/// failed connection
+const MAX_RETRIES: u32 = 3;
+
+/// The longest a `Retry-After` may ask to wait.  Longer, and the request
+/// fails
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// How long to wait before trying again for the `attempt`th time: as
+/// long as a rate limit says, or longer each time.  `None` if the rate
+/// limit asks for more than `MAX_WAIT`
+fn wait(attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+    match retry_after {
+        Some(retry_after) if retry_after > MAX_WAIT => None,
+        Some(retry_after) => Some(retry_after),
+        None => Some(Duration::from_secs(1 << attempt)),
+    }
+}
+
+/// End points that create nothing, so a request that timed out, which
+/// the server may have carried out, can be made again.  Others, such as
+/// fine-tuning jobs, batches, uploads and images, would be duplicated
+const IDEMPOTENT: [&str; 4] = [
+    "/chat/completions",
+    "/completions",
+    "/embeddings",
+    "/moderations",
+];
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Could not make the request, or read the response
+    Http(reqwest::Error),
+    /// The API answered with an error
+    Status { status: StatusCode, message: String },
+    /// The response was not what was expected
+    Json(String),
+    /// Recording, or replaying, failed
+    Cassette(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Http(err) => write!(f, "HTTP: {err}"),
+            ApiError::Status { status, message } => write!(
+                f,
+                "Failed: Status: {} {}. {message}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or("Unknown Reason"),
+            ),
+            ApiError::Json(err) => write!(f, "Bad response: {err}"),
+            ApiError::Cassette(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(err: reqwest::Error) -> Self {
+        ApiError::Http(err)
+    }
+}
+
+/// A response from the API, and the key to cache it under
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    key: String,
+    response: String,
+}
+
+/// Where a response from `post_json_cached` came from
+#[derive(Debug, Clone, Default)]
+pub enum CacheStatus {
+    /// The API, with no cache
+    #[default]
+    Uncached,
+    /// The cache
+    Hit,
+    /// The API.  See `ApiClient::keep`
+    Miss(CacheEntry),
+}
+
+impl CacheStatus {
+    pub fn is_hit(&self) -> bool {
+        matches!(self, CacheStatus::Hit)
+    }
+}
+
+pub struct ApiClient {
+    client: Client,
+    credentials: Credentials,
+    base_url: String,
+    /// Record requests and responses, or replay them
+    cassette: Option<Cassette>,
+    /// Answers to requests made before
+    cache: Option<ResponseCache>,
+    /// Where the tokens used are recorded
+    ledger: Option<Ledger>,
+}
+
+impl ApiClient {
+    pub fn new(client: Client, api_key: ApiKey) -> Self {
+        Self {
+            client,
+            credentials: Credentials::new(api_key),
+            base_url: OPENAI_URL.to_string(),
+            cassette: None,
+            cache: None,
+            ledger: None,
+        }
+    }
+
+    /// Make requests as `credentials`, with its organization and project
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Switch credentials, for `> key`
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// Record the tokens each response says it used in `ledger`
+    pub fn with_ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    /// Answer requests that have been made before from `cache`.  Only
+    /// requests made with `post_json_cached` are cached
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_ref()
+    }
+
+    /// Record, or replay, every request.  See `cassette`
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Send requests somewhere other than OpenAI, e.g. the mock server
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn post_json<B: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<R, ApiError> {
+        let body = self.send(|| self.client.post(self.url(path)).json(body))?;
+        Self::json(&body)
+    }
+
+    /// As `post_json`, but from the cache if the same request has been
+    /// made before, with the same credentials, to the same API, unless
+    /// `fresh`.  Also says where the response came from.  A response
+    /// from the API is only cached by `keep`, once its answer is accepted
+    pub fn post_json_cached<B: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+        fresh: bool,
+    ) -> Result<(R, CacheStatus), ApiError> {
+        if self.cache.is_none() {
+            return Ok((self.post_json(path, body)?, CacheStatus::Uncached));
+        }
+        let request = serde_json::to_vec(body).map_err(|err| ApiError::Json(err.to_string()))?;
+        let key = self.cache_key(path, &request);
+        if !fresh {
+            let cached = self.cache.as_ref().and_then(|cache| cache.get(&key));
+            if let Some(Ok(response)) = cached.map(|cached| serde_json::from_str(&cached)) {
+                return Ok((response, CacheStatus::Hit));
+            }
+        }
+        let response = self.send(|| self.client.post(self.url(path)).json(body))?;
+        let entry = CacheEntry {
+            key,
+            response: String::from_utf8_lossy(&response).to_string(),
+        };
+        Ok((Self::json(&response)?, CacheStatus::Miss(entry)))
+    }
+
+    /// Cache a response from `post_json_cached` whose answer was accepted
+    pub fn keep(&self, status: &CacheStatus) {
+        if let (Some(cache), CacheStatus::Miss(entry)) = (self.cache.as_ref(), status) {
+            if let Err(err) = cache.put(&entry.key, &entry.response) {
+                eprintln!("Cache: {err}");
+            }
+        }
+    }
+
+    /// Answers for one key, profile, organization or project, or from
+    /// one API, are not given to another.  The key is in the scope only
+    /// as a hash
+    fn cache_key(&self, path: &str, request: &[u8]) -> String {
+        let credentials = &self.credentials;
+        let scope = format!(
+            "{} {} {} {} {}",
+            self.url(path),
+            embeddings::sha256(credentials.key.expose().as_bytes()),
+            credentials.profile,
+            credentials.organization.as_deref().unwrap_or_default(),
+            credentials.project.as_deref().unwrap_or_default()
+        );
+        cassette::key("POST", &scope, request)
+    }
+
+    pub fn get_json<R: DeserializeOwned>(&self, path: &str) -> Result<R, ApiError> {
+        let body = self.send(|| self.client.get(self.url(path)))?;
+        Self::json(&body)
+    }
+
+    pub fn delete_json<R: DeserializeOwned>(&self, path: &str) -> Result<R, ApiError> {
+        let body = self.send(|| self.client.delete(self.url(path)))?;
+        Self::json(&body)
+    }
+
+    /// GET the response body as it is, for downloads
+    pub fn get_bytes(&self, path: &str) -> Result<Vec<u8>, ApiError> {
+        self.send(|| self.client.get(self.url(path)))
+    }
+
+    /// POST a JSON body and return the response body as it is, for end
+    /// points that answer with audio or files
+    pub fn post_bytes<B: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<Vec<u8>, ApiError> {
+        self.send(|| self.client.post(self.url(path)).json(body))
+    }
+
+    /// POST a multipart form and return the body of the response as
+    /// text.  A form can only be sent once so `form` makes a new one for
+    /// each attempt
+    pub fn post_multipart(&self, path: &str, form: &dyn Fn() -> Form) -> Result<String, ApiError> {
+        let body = self.send(|| self.client.post(self.url(path)).multipart(form()))?;
+        Ok(String::from_utf8_lossy(&body).to_string())
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Authenticate and send the request made by `build`, trying again
+    /// if it may succeed later, and return the body of the response.
+    /// Responses other than success are returned as `ApiError::Status`
+    fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Vec<u8>, ApiError> {
+        let mut attempt = 0;
+        loop {
+            let mut builder = build().bearer_auth(self.credentials.key.expose());
+            if let Some(organization) = self.credentials.organization.as_deref() {
+                builder = builder.header("OpenAI-Organization", organization);
+            }
+            if let Some(project) = self.credentials.project.as_deref() {
+                builder = builder.header("OpenAI-Project", project);
+            }
+            let request = builder.build()?;
+            let idempotent = is_idempotent(&request);
+            let result = self.exchange(request);
+            let retry = match result.as_ref() {
+                Ok((status, _, _)) => should_retry(*status),
+                Err(ApiError::Http(err)) => err.is_connect() || (err.is_timeout() && idempotent),
+                Err(_) => false,
+            };
+            let retry_after = match result.as_ref() {
+                Ok((_, _, retry_after)) => *retry_after,
+                Err(_) => None,
+            };
+            if retry && attempt < MAX_RETRIES {
+                let Some(wait) = wait(attempt + 1, retry_after) else {
+                    eprintln!(
+                        "The API asked to wait longer than {}s, so the request is not tried again",
+                        MAX_WAIT.as_secs()
+                    );
+                    let (status, body, _) = result?;
+                    return Err(ApiError::Status {
+                        status,
+                        message: error_message(&String::from_utf8_lossy(&body)),
+                    });
+                };
+                attempt += 1;
+                eprintln!(
+                    "Request failed, trying again in {:.1}s ({attempt}/{MAX_RETRIES})",
+                    wait.as_secs_f64()
+                );
+                if self.cassette.as_ref().map(|c| c.mode) != Some(Mode::Replay) {
+                    thread::sleep(wait);
+                }
+                continue;
+            }
+            let (status, body, _) = result?;
+            if !status.is_success() {
+                return Err(ApiError::Status {
+                    status,
+                    message: error_message(&String::from_utf8_lossy(&body)),
+                });
+            }
+            return Ok(body);
+        }
+    }
+
+    /// Send `request`, or replay it from the cassette, and return the
+    /// status and body of the response, and how long it says to wait
+    /// before trying again.  Records it if recording
+    fn exchange(
+        &self,
+        request: reqwest::blocking::Request,
+    ) -> Result<(StatusCode, Vec<u8>, Option<Duration>), ApiError> {
+        let method = request.method().to_string();
+        let path = match request.url().query() {
+            Some(query) => format!("{}?{query}", request.url().path()),
+            None => request.url().path().to_string(),
+        };
+        // Uploads are streamed, and are keyed by their path alone
+        let body = request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .unwrap_or_default()
+            .to_vec();
+        let cassette = match self.cassette.as_ref() {
+            Some(cassette) if cassette.mode == Mode::Replay => {
+                let exchange = cassette
+                    .replay(&method, &path, &body)
+                    .map_err(ApiError::Cassette)?;
+                let status = StatusCode::from_u16(exchange.status)
+                    .map_err(|err| ApiError::Cassette(err.to_string()))?;
+                return Ok((status, exchange.body().map_err(ApiError::Cassette)?, None));
+            }
+            cassette => cassette,
+        };
+        let response = self.client.execute(request)?;
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let response = response.bytes()?.to_vec();
+        if let Some(ledger) = self.ledger.as_ref().filter(|_| status.is_success()) {
+            ledger.record_response(&self.credentials, &response);
+        }
+        if let Some(cassette) = cassette {
+            cassette
+                .record(
+                    &method,
+                    &path,
+                    &body,
+                    status.as_u16(),
+                    &response,
+                    self.credentials.key.expose(),
+                )
+                .map_err(ApiError::Cassette)?;
+        }
+        Ok((status, response, retry_after))
+    }
+
+    /// Decode the body of a successful response
+    fn json<R: DeserializeOwned>(body: &[u8]) -> Result<R, ApiError> {
+        serde_json::from_slice(body).map_err(|err| ApiError::Json(err.to_string()))
+    }
+}
+
+/// Reads, deletes, and requests to `IDEMPOTENT` end points
+fn is_idempotent(request: &reqwest::blocking::Request) -> bool {
+    let method = request.method();
+    method == Method::GET
+        || method == Method::DELETE
+        || IDEMPOTENT
+            .iter()
+            .any(|end| request.url().path().ends_with(end))
+}
+
+/// `retry-after-ms`, which OpenAI sends, or `retry-after` in seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let number = |name: &str| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite() && *n >= 0.0)
+    };
+    // Too long to hold is too long to wait
+    let seconds = |n: f64| Duration::try_from_secs_f64(n).unwrap_or(Duration::MAX);
+    number("retry-after-ms")
+        .map(|ms| seconds(ms / 1000.0))
+        .or_else(|| number("retry-after").map(seconds))
+}
+
+/// Rate limits and server errors are worth trying again
+fn should_retry(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// OpenAI errors look like `{"error": {"message": "..."}}`.  Anything
+/// else is returned as it is
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.pointer("/error/message")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{self, ChatMessage, ChatRequest};
+    use crate::completions::{self, CompletionRequestInfo};
+    use crate::mock_server::{Fixture, MockServer, DEFAULT_ANSWER};
+    use crate::sampling::Sampling;
+    use reqwest::blocking::Client;
+    use reqwest::header::HeaderValue;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn retry_after_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(250)));
+        headers.insert("retry-after-ms", HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.remove("retry-after-ms");
+        headers.insert("retry-after", HeaderValue::from_static("1e30"));
+        assert_eq!(retry_after(&headers), Some(Duration::MAX));
+        headers.insert(
+            "retry-after",
+            HeaderValue::from_static("Wed, 21 Oct 2026 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn long_waits_are_refused() {
+        assert_eq!(wait(1, None), Some(Duration::from_secs(2)));
+        assert_eq!(wait(3, None), Some(Duration::from_secs(8)));
+        let rate_limit = Some(Duration::from_millis(250));
+        assert_eq!(wait(1, rate_limit), rate_limit);
+        assert_eq!(wait(1, Some(MAX_WAIT)), Some(MAX_WAIT));
+        assert_eq!(wait(1, Some(Duration::from_secs(3600))), None);
+    }
+
+    #[test]
+    fn cache_keys() {
+        let api = || ApiClient::new(Client::new(), ApiKey::new("sk-test"));
+        let key = |api: &ApiClient| api.cache_key("/chat/completions", b"{}");
+        let mut work = Credentials::new(ApiKey::new("sk-test"));
+        work.project = Some("proj_1".to_string());
+        assert_eq!(key(&api()), key(&api()));
+        assert_ne!(key(&api()), key(&api().with_credentials(work)));
+        // The same profile, with another key
+        let other = ApiClient::new(Client::new(), ApiKey::new("sk-other"));
+        assert_ne!(key(&api()), key(&other));
+        assert_ne!(
+            key(&api()),
+            key(&api().with_base_url("http://127.0.0.1:8080/v1"))
+        );
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_repeated() {
+        let client = Client::new();
+        let request = |builder: RequestBuilder| builder.build().unwrap();
+        let url = |path: &str| format!("{OPENAI_URL}{path}");
+        assert!(is_idempotent(&request(client.get(url("/files")))));
+        assert!(is_idempotent(&request(client.delete(url("/files/f")))));
+        assert!(is_idempotent(&request(
+            client.post(url("/chat/completions"))
+        )));
+        assert!(!is_idempotent(&request(
+            client.post(url("/fine_tuning/jobs"))
+        )));
+        assert!(!is_idempotent(&request(
+            client.post(url("/images/generations"))
+        )));
+    }
+
+    fn fixtures(json: &str) -> Vec<Fixture> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn client_against_mock() {
+        let server = MockServer::start(
+            0,
+            fixtures(
+                r#"[{"path": "/v1/chat/completions", "contains": "bad", "status": 400,
+                     "body": {"error": {"message": "Bad prompt"}}, "times": 1},
+                    {"path": "/v1/chat/completions", "answer": "Hello there"}]"#,
+            ),
+        )
+        .unwrap();
+        let mut api =
+            ApiClient::new(Client::new(), ApiKey::new("sk-test")).with_base_url(&server.url);
+
+        let mut request =
+            CompletionRequestInfo::new("Q: Hi\nA:".to_string(), "davinci".to_string(), 0.5, 100);
+        request.n = 2;
+        let response = completions::complete(&api, &request).unwrap();
+        assert_eq!(response.choices.len(), 2);
+        assert_eq!(response.choices[1].text, DEFAULT_ANSWER);
+
+        let mut work = Credentials::new(ApiKey::new("sk-work"));
+        work.organization = Some("org-1".to_string());
+        work.project = Some("proj_1".to_string());
+        api.set_credentials(work);
+        let messages = [ChatMessage::user("bad")];
+        let sampling = Sampling::default();
+        let chat_request = ChatRequest {
+            model: "gpt-4o",
+            messages: &messages,
+            temperature: 0.5,
+            max_tokens: 100,
+            n: 1,
+            logprobs: None,
+            top_logprobs: None,
+            tools: &[],
+            tool_choice: None,
+            sampling: &sampling,
+            fresh: false,
+        };
+        match chat::complete(&api, &chat_request) {
+            Err(ApiError::Status { status, message }) => {
+                assert_eq!(status.as_u16(), 400);
+                assert_eq!(message, "Bad prompt");
+            }
+            other => panic!("{other:?}"),
+        }
+        // The error fixture was used up
+        let response = chat::complete(&api, &chat_request).unwrap();
+        assert_eq!(response.choices[0].message.text(), "Hello there");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/v1/completions");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer sk-test"));
+        assert_eq!(requests[0].header("openai-project"), None);
+        assert_eq!(requests[1].header("authorization"), Some("Bearer sk-work"));
+        assert_eq!(requests[1].header("openai-organization"), Some("org-1"));
+        assert_eq!(requests[1].header("openai-project"), Some("proj_1"));
+        assert_eq!(requests[2].json()["messages"][0]["content"], "bad");
+    }
+
+    #[test]
+    fn mock_streams_and_models() {
+        let server = MockServer::start(0, Vec::new()).unwrap();
+        let body = Client::new()
+            .post(format!("{}/chat/completions", server.url))
+            .json(&json!({"model": "gpt-4o", "stream": true, "messages": []}))
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        let events: Vec<&str> = body
+            .split("\n\n")
+            .filter_map(|e| e.strip_prefix("data: "))
+            .collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let text: String = events
+            .iter()
+            .filter_map(|e| serde_json::from_str::<Value>(e).ok())
+            .filter_map(|c| {
+                c["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(text, DEFAULT_ANSWER);
+
+        let api = ApiClient::new(Client::new(), ApiKey::new("sk-test")).with_base_url(&server.url);
+        let models: Value = api.get_json("/models").unwrap();
+        assert!(!models["data"].as_array().unwrap().is_empty());
+        match api.get_json::<Value>("/nowhere") {
+            Err(ApiError::Status { status, .. }) => assert_eq!(status.as_u16(), 404),
+            other => panic!("{other:?}"),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Finding the API key.  The first of these that is set is used:
+//!
+//! 1. `--api-key`, which is visible in `ps` and shell history, so it
+//!    is discouraged
+//! 2. A command in the `api_key` section of the configuration that
+//!    prints the key, e.g. `pass show openai`
+//! 3. A file, `--api-key-file`, the `api_key` section's `file`, or
+//!    `~/.config/open_ai_chat_gpt3/api_key` if it exists.  Only its
+//!    owner may be able to read it
+//! 4. $OPENAI_API_KEY, or the variable named by the section's `env`
+//! 5. Asking, without echoing what is typed, if there is a terminal
+//!
+//! ```json
+//! {"api_key": {"command": "pass show openai"}}
+//! ```
+//!
+//! The key is kept in an `ApiKey`, which is never displayed
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+const ENV: &str = "OPENAI_API_KEY";
+
+/// The key file used if no other is given, under $HOME
+const DEFAULT_FILE: &str = ".config/open_ai_chat_gpt3/api_key";
+
+/// The `api_key` section of the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyConfig {
+    /// A command that prints the key.  Split on white space, not run
+    /// by a shell
+    pub command: Option<String>,
+    /// A file with the key.  `~/` is the home directory
+    pub file: Option<PathBuf>,
+    /// The environment variable with the key, instead of OPENAI_API_KEY
+    pub env: Option<String>,
+}
+
+/// A secret key.  `Debug` does not show it, and there is no `Display`
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new(key: &str) -> Self {
+        Self(key.trim().to_string())
+    }
+
+    /// The key itself, for the Authorization header
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKey(REDACTED)")
+    }
+}
+
+impl FromStr for ApiKey {
+    type Err = std::convert::Infallible;
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(key))
+    }
+}
+
+/// Where the key came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Argument,
+    Command(String),
+    File(PathBuf),
+    Environment(String),
+    Prompt,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Argument => write!(f, "--api-key"),
+            Source::Command(command) => write!(f, "the command `{command}`"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Environment(variable) => write!(f, "${variable}"),
+            Source::Prompt => write!(f, "the terminal"),
+        }
+    }
+}
+
+/// Find the key.  `argument` is `--api-key` and `file` `--api-key-file`
+pub fn resolve(
+    argument: Option<&ApiKey>,
+    file: Option<&Path>,
+    config: &KeyConfig,
+) -> Result<(ApiKey, Source), String> {
+    if let Some(key) = argument {
+        eprintln!(
+            "Warning: --api-key can be seen in `ps` and shell history.  \
+             Use ${ENV}, a key file or a key command instead"
+        );
+        return Ok((key.clone(), Source::Argument));
+    }
+    if let Some(command) = config.command.as_deref() {
+        return Ok((from_command(command)?, Source::Command(command.to_string())));
+    }
+    let file = file
+        .map(Path::to_path_buf)
+        .or_else(|| config.file.as_deref().map(expand_home))
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(DEFAULT_FILE))
+                .filter(|path| path.exists())
+        });
+    if let Some(path) = file {
+        return Ok((from_file(&path)?, Source::File(path)));
+    }
+    let variable = config.env.as_deref().unwrap_or(ENV);
+    if let Some(key) = env::var(variable).ok().filter(|key| !key.trim().is_empty()) {
+        return Ok((ApiKey::new(&key), Source::Environment(variable.to_string())));
+    }
+    if std::io::stdin().is_terminal() {
+        let key = rpassword::prompt_password("OpenAI API key: ").map_err(|err| err.to_string())?;
+        if !key.trim().is_empty() {
+            return Ok((ApiKey::new(&key), Source::Prompt));
+        }
+    }
+    Err(format!(
+        "No API key.  Set ${ENV}, or in the configuration an `api_key` \
+         section with a `command` that prints it or a `file` with it"
+    ))
+}
+
+/// The key from `config`'s own `command`, `file` or `env`, for a
+/// profile.  There is no falling back to the default file,
+/// $OPENAI_API_KEY or asking: a profile names where its key is
+pub fn from_config(config: &KeyConfig) -> Result<ApiKey, String> {
+    if let Some(command) = config.command.as_deref() {
+        return from_command(command);
+    }
+    if let Some(file) = config.file.as_deref() {
+        return from_file(&expand_home(file));
+    }
+    match config.env.as_deref() {
+        Some(variable) => env::var(variable)
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| ApiKey::new(&key))
+            .ok_or_else(|| format!("${variable} is not set")),
+        None => Err("No API key.  Give it a `command`, `file` or `env`".to_string()),
+    }
+}
+
+/// `~/` at the start of `path` is $HOME
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The first line the command prints.  What it prints is not included
+/// in errors, in case it is the key
+fn from_command(command: &str) -> Result<ApiKey, String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let (programme, args) = words.split_first().ok_or("The API key command is empty")?;
+    let output = Command::new(programme)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("API key command `{command}`: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "API key command `{command}` failed: {}",
+            output.status
+        ));
+    }
+    first_line(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| format!("API key command `{command}` printed nothing"))
+}
+
+/// The first line of the file, which only its owner may read
+fn from_file(path: &Path) -> Result<ApiKey, String> {
+    check_permissions(path)?;
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    first_line(&text).ok_or_else(|| format!("{}: there is no key in it", path.display()))
+}
+
+fn first_line(text: &str) -> Option<ApiKey> {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(ApiKey::new)
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)
+        .map_err(|err| format!("{}: {err}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "{}: others can read the API key (mode {:o}).  `chmod 600 {0}`",
+            path.display(),
+            mode & 0o777
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sources() {
+        let key = ApiKey::new("sk-secret\n");
+        assert_eq!(key.expose(), "sk-secret");
+        assert!(!format!("{:?}", Some(&key)).contains("sk-secret"));
+
+        let config = KeyConfig {
+            command: Some("echo sk-from-command".to_string()),
+            file: None,
+            env: None,
+        };
+        let (key, source) = resolve(None, None, &config).unwrap();
+        assert_eq!(key.expose(), "sk-from-command");
+        assert_eq!(source, Source::Command("echo sk-from-command".to_string()));
+        let failing = KeyConfig {
+            command: Some("false".to_string()),
+            file: None,
+            env: None,
+        };
+        assert!(resolve(None, None, &failing).is_err());
+
+        let path = env::temp_dir().join(format!("api_key_test_{}", std::process::id()));
+        fs::write(&path, "\nsk-from-file\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            let err = resolve(None, Some(&path), &KeyConfig::default()).unwrap_err();
+            assert!(err.contains("chmod 600") && !err.contains("sk-from-file"));
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let (key, source) = resolve(None, Some(&path), &KeyConfig::default()).unwrap();
+        assert_eq!(key.expose(), "sk-from-file");
+        assert_eq!(source, Source::File(path.clone()));
+        fs::remove_file(&path).unwrap();
+    }
+}
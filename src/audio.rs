@@ -0,0 +1,210 @@
+//! Speech to text and text to speech.  See
+//! https://platform.openai.com/docs/api-reference/audio
+use crate::api_client::ApiClient;
+use reqwest::blocking::multipart::{Form, Part};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const TRANSCRIPTION_MODEL: &str = "whisper-1";
+pub const SPEECH_MODEL: &str = "tts-1";
+
+/// What `/v1/audio/transcriptions` can return
+const TRANSCRIPTION_FORMATS: [&str; 5] = ["json", "text", "srt", "verbose_json", "vtt"];
+
+/// What `/v1/audio/speech` can return.  The first is the default
+const SPEECH_FORMATS: [&str; 6] = ["mp3", "opus", "aac", "flac", "wav", "pcm"];
+
+#[derive(Debug, Clone)]
+pub struct TranscribeOptions {
+    pub model: String,
+    /// ISO-639-1 language of the audio, e.g. "en"
+    pub language: Option<String>,
+    /// Text to guide the style, or continue an earlier segment
+    pub prompt: Option<String>,
+    /// One of `TRANSCRIPTION_FORMATS`.  The API default is "json"
+    pub response_format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SpeechRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: &'a str,
+    response_format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpeechOptions {
+    pub model: String,
+    /// "alloy", "echo", "fable", "onyx", "nova", "shimmer"...
+    pub voice: String,
+    /// One of `SPEECH_FORMATS`.  If not set it comes from the file name
+    pub format: Option<String>,
+    /// 0.25 to 4.0
+    pub speed: Option<f32>,
+}
+
+/// Transcribe the audio in `path`.  For "json" the text is returned, for
+/// "verbose_json" the JSON pretty printed, and for the others the
+/// response as it is
+pub fn transcribe(
+    api: &ApiClient,
+    path: &Path,
+    options: &TranscribeOptions,
+) -> Result<String, String> {
+    let format = options.response_format.as_deref().unwrap_or("json");
+    if !TRANSCRIPTION_FORMATS.contains(&format) {
+        return Err(format!(
+            "Response format must be one of: {}",
+            TRANSCRIPTION_FORMATS.join(", ")
+        ));
+    }
+    let bytes = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "audio".to_string());
+    let form = || {
+        let mut form = Form::new()
+            .part(
+                "file",
+                Part::bytes(bytes.clone()).file_name(file_name.clone()),
+            )
+            .text("model", options.model.clone())
+            .text("response_format", format.to_string());
+        if let Some(language) = options.language.as_ref() {
+            form = form.text("language", language.clone());
+        }
+        if let Some(prompt) = options.prompt.as_ref() {
+            form = form.text("prompt", prompt.clone());
+        }
+        form
+    };
+    let body = api
+        .post_multipart("/audio/transcriptions", &form)
+        .map_err(|e| e.to_string())?;
+    transcription_text(format, &body)
+}
+
+/// The part of a transcription response to display
+fn transcription_text(format: &str, body: &str) -> Result<String, String> {
+    match format {
+        "json" | "verbose_json" => {
+            let value: serde_json::Value =
+                serde_json::from_str(body).map_err(|err| format!("Bad response: {err}"))?;
+            if format == "json" {
+                value["text"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| "The response has no text".to_string())
+            } else {
+                Ok(serde_json::to_string_pretty(&value).unwrap())
+            }
+        }
+        _ => Ok(body.to_string()),
+    }
+}
+
+/// The audio format for `output`: `format` if it is set, otherwise its
+/// extension if that is a format, otherwise mp3
+pub fn speech_format(format: Option<&str>, output: &Path) -> Result<String, String> {
+    let format = match format {
+        Some(format) => format.to_string(),
+        None => output
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .filter(|e| SPEECH_FORMATS.contains(&e.as_str()))
+            .unwrap_or_else(|| SPEECH_FORMATS[0].to_string()),
+    };
+    if SPEECH_FORMATS.contains(&format.as_str()) {
+        Ok(format)
+    } else {
+        Err(format!(
+            "Format must be one of: {}",
+            SPEECH_FORMATS.join(", ")
+        ))
+    }
+}
+
+/// Say `text` and save the audio in `output`
+pub fn speak(
+    api: &ApiClient,
+    text: &str,
+    options: &SpeechOptions,
+    output: &Path,
+) -> Result<(), String> {
+    let format = speech_format(options.format.as_deref(), output)?;
+    let request = SpeechRequest {
+        model: &options.model,
+        input: text,
+        voice: &options.voice,
+        response_format: &format,
+        speed: options.speed,
+    };
+    let bytes = api
+        .post_bytes("/audio/speech", &request)
+        .map_err(|e| e.to_string())?;
+    fs::write(output, bytes).map_err(|err| format!("{}: {err}", output.display()))
+}
+
+/// Transcribe `path` and display the text, or write it to `output`.  For
+/// the `transcribe` subcommand
+pub fn run_transcribe(
+    api: &ApiClient,
+    path: &Path,
+    options: &TranscribeOptions,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let text = transcribe(api, path, options)?;
+    match output {
+        Some(output) => {
+            fs::write(output, &text).map_err(|err| format!("{}: {err}", output.display()))?;
+            println!("Saved {}", output.display());
+        }
+        None => println!("{text}"),
+    }
+    Ok(())
+}
+
+/// Say `text` and display where it was saved.  For the `speak`
+/// subcommand.  Without `output` the file is "speech." and the format
+pub fn run_speak(
+    api: &ApiClient,
+    text: &str,
+    options: &SpeechOptions,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => PathBuf::from(format!(
+            "speech.{}",
+            speech_format(options.format.as_deref(), Path::new(""))?
+        )),
+    };
+    speak(api, text, options, &output)?;
+    println!("Saved {}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn formats() {
+        assert_eq!(speech_format(None, Path::new("out.wav")).unwrap(), "wav");
+        assert_eq!(speech_format(None, Path::new("out.txt")).unwrap(), "mp3");
+        assert_eq!(
+            speech_format(Some("opus"), Path::new("out.mp3")).unwrap(),
+            "opus"
+        );
+        assert!(speech_format(Some("ogg"), Path::new("out.ogg")).is_err());
+        assert_eq!(
+            transcription_text("json", r#"{"text": "Hello"}"#).unwrap(),
+            "Hello"
+        );
+        assert_eq!(transcription_text("srt", "1\n").unwrap(), "1\n");
+    }
+}
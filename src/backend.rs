@@ -0,0 +1,327 @@
+//! Sending a prompt, with the conversation so far, to whichever end
+//! point the model uses, and turning the response into `Choice`s.  For
+//! chat models this is also where tool calls are run: the model is
+//! asked again with the results until it gives an answer
+use crate::api_client::{ApiClient, CacheStatus};
+use crate::chat::{self, ChatMessage, ChatRequest, ImageUrl, ToolCall};
+use crate::completions::{self, Choice, CompletionRequestInfo};
+use crate::conversation::Conversation;
+use crate::json_schema::JsonSchemaFormat;
+use crate::moderation::{self, Action, ModerationConfig, Verdict};
+use crate::tools::{self, ToolConfig, ToolSpec};
+use crate::usage::Usage;
+use crate::workspace::Workspace;
+
+/// Give up if the model keeps calling tools
+const MAX_TOOL_ROUNDS: usize = 20;
+
+pub struct Backend {
+    pub api: ApiClient,
+    /// Use the chat completions end point, even for models that are not
+    /// chat models
+    pub chat: bool,
+    pub tools: Vec<ToolConfig>,
+    /// The built in tools, if enabled
+    pub workspace: Option<Workspace>,
+    /// Answers must match this
+    pub schema: Option<JsonSchemaFormat>,
+    /// How many times to ask again for an answer that matches `schema`
+    pub json_retries: u32,
+    /// Check prompts, and maybe answers, with the moderation end point
+    pub moderation: Option<ModerationConfig>,
+}
+
+/// The result of asking
+pub struct Answers {
+    pub choices: Vec<Choice>,
+    /// Tool calls made, and their results, before the answers
+    pub tool_messages: Vec<ChatMessage>,
+    /// Where the answers came from
+    pub cache: CacheStatus,
+    /// Tokens used by every request made for the answers
+    pub usage: Usage,
+}
+
+/// Answers that did not match the schema, and the tool calls made
+/// before them, for asking again without calling the tools again
+#[derive(Default)]
+struct Retry {
+    tool_messages: Vec<ChatMessage>,
+    /// Each answer, and what was wrong with it
+    rejected: Vec<(String, String)>,
+}
+
+/// What the model is told about an answer that did not match the schema
+fn rejection(error: &str) -> String {
+    format!("That does not match the JSON schema: {error}\nAnswer again, with only JSON that matches it.")
+}
+
+impl Backend {
+    /// Ask `prompt`, with its `images`, with `conversation` as context.
+    /// `partial` is the start of an answer to continue.  `request_info`
+    /// has the model and parameters.  `confirm` is asked before a tool
+    /// is run, unless the tool is `auto_approve`.  If there is a schema
+    /// choices that do not match it are dropped, and if none do the model
+    /// is told why and asked again.  The text of choices that match is
+    /// the JSON, pretty printed.  Answers that ran out of tokens are kept
+    /// as they are, to be continued, and a continuation matches if the
+    /// whole answer does.  The answers are not kept in the cache: the
+    /// caller keeps them once it has accepted them
+    pub fn ask(
+        &self,
+        request_info: &CompletionRequestInfo,
+        conversation: &Conversation,
+        prompt: &str,
+        images: &[ImageUrl],
+        partial: Option<&str>,
+        confirm: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Answers, String> {
+        let mut retry = Retry::default();
+        let schema = match self.schema.as_ref() {
+            Some(schema) => schema,
+            None => {
+                return self.send(
+                    request_info,
+                    conversation,
+                    prompt,
+                    images,
+                    partial,
+                    &retry,
+                    confirm,
+                );
+            }
+        };
+        let mut usage = Usage::default();
+        let mut attempt = 0;
+        loop {
+            let mut answers = self.send(
+                request_info,
+                conversation,
+                prompt,
+                images,
+                partial,
+                &retry,
+                confirm,
+            )?;
+            usage.add(&answers.usage);
+            let mut failures = Vec::new();
+            answers.choices.retain_mut(|choice| {
+                if choice.finish_reason.as_deref() == Some("length") {
+                    return true;
+                }
+                let whole = format!("{}{}", partial.unwrap_or_default(), choice.text);
+                match schema.check(&whole) {
+                    Ok(value) if partial.is_none() => {
+                        choice.text = serde_json::to_string_pretty(&value).unwrap();
+                        true
+                    }
+                    Ok(_) => true,
+                    Err(err) => {
+                        failures.push((choice.text.clone(), err));
+                        false
+                    }
+                }
+            });
+            if !answers.choices.is_empty() {
+                answers.usage = usage;
+                return Ok(answers);
+            }
+            let failure = failures
+                .iter()
+                .map(|(_, err)| err.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if partial.is_some() {
+                return Err(format!(
+                    "The continued answer does not match the schema:\n{failure}"
+                ));
+            }
+            if attempt == self.json_retries {
+                return Err(format!("No answer matched the schema:\n{failure}"));
+            }
+            attempt += 1;
+            println!(
+                "Answer did not match the schema, asking again ({attempt}/{}):\n{failure}",
+                self.json_retries
+            );
+            // Only the last request is made again, with what was wrong
+            retry.tool_messages = answers.tool_messages;
+            retry.rejected.extend(failures.into_iter().take(1));
+        }
+    }
+
+    /// Does `model` use the chat completions end point?
+    pub fn uses_chat(&self, model: &str) -> bool {
+        self.chat || chat::is_chat_model(model)
+    }
+
+    /// Check `text`, a "prompt" or an "answer", if moderation is on for
+    /// it.  Categories that warn or block are displayed.  If the check
+    /// itself fails the text is blocked
+    pub fn moderate(&self, input: &'static str, text: &str) -> Option<Verdict> {
+        let config = self.moderation.as_ref()?;
+        if input == "answer" && !config.answers {
+            return None;
+        }
+        let verdict = moderation::check(&self.api, config, input, text).unwrap_or_else(|err| {
+            println!("{err}");
+            Verdict {
+                input,
+                action: Action::Block,
+                categories: Vec::new(),
+            }
+        });
+        if verdict.action != Action::Pass {
+            print!("\x1b[1;33m{}\x1b[0m", verdict.describe());
+        }
+        Some(verdict)
+    }
+
+    /// Ask once.  If `retry` has rejected answers the model is told
+    /// what was wrong with them, and may not call tools
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &self,
+        request_info: &CompletionRequestInfo,
+        conversation: &Conversation,
+        prompt: &str,
+        images: &[ImageUrl],
+        partial: Option<&str>,
+        retry: &Retry,
+        confirm: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Answers, String> {
+        if !self.uses_chat(&request_info.model) {
+            if !images.is_empty() {
+                return Err(format!("{} cannot take images", request_info.model));
+            }
+            let mut request_info = request_info.clone();
+            request_info.prompt = match partial {
+                Some(partial) => conversation.render_continuation(prompt, partial),
+                None => conversation.render_prompt(prompt),
+            };
+            for (answer, error) in retry.rejected.iter() {
+                request_info.prompt.push_str(&format!(
+                    " {}\nQ: {}\nA:",
+                    answer.trim(),
+                    rejection(error)
+                ));
+            }
+            let json =
+                completions::complete(&self.api, &request_info).map_err(|e| e.to_string())?;
+            return Ok(Answers {
+                choices: json.choices,
+                tool_messages: Vec::new(),
+                cache: json.cache,
+                usage: json.usage.unwrap_or_default(),
+            });
+        }
+
+        let mut specs: Vec<ToolSpec> = self.tools.iter().map(ToolConfig::spec).collect();
+        if let Some(workspace) = self.workspace.as_ref() {
+            // Tools from the configuration take precedence
+            specs.extend(
+                workspace
+                    .specs()
+                    .iter()
+                    .filter(|s| !self.tools.iter().any(|t| t.name == s.name()))
+                    .cloned(),
+            );
+        }
+        let mut messages = conversation.messages(prompt, images, partial);
+        let mut tool_messages = retry.tool_messages.clone();
+        messages.extend(tool_messages.iter().cloned());
+        for (answer, error) in retry.rejected.iter() {
+            messages.push(ChatMessage::assistant(answer));
+            messages.push(ChatMessage::user(&rejection(error)));
+        }
+        let mut usage = Usage::default();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let request = ChatRequest {
+                model: &request_info.model,
+                messages: &messages,
+                temperature: request_info.temperature,
+                max_tokens: request_info.max_tokens,
+                // Only the first answer's tool calls would be run
+                n: if specs.is_empty() { request_info.n } else { 1 },
+                logprobs: request_info.logprobs.map(|_| true),
+                top_logprobs: request_info.logprobs,
+                tools: &specs,
+                tool_choice: (!retry.rejected.is_empty() && !specs.is_empty()).then_some("none"),
+                sampling: &request_info.sampling,
+                fresh: request_info.fresh,
+            };
+            let response = chat::complete(&self.api, &request).map_err(|e| e.to_string())?;
+            usage.add(&response.usage.unwrap_or_default());
+            let calls = response
+                .choices
+                .first()
+                .and_then(|c| c.message.tool_calls.clone())
+                .unwrap_or_default();
+            if calls.is_empty() {
+                let choices = response
+                    .choices
+                    .into_iter()
+                    .map(|c| Choice {
+                        text: c.message.text(),
+                        logprobs: c.logprobs.map(|l| l.to_logprobs()),
+                        finish_reason: c.finish_reason,
+                        index: c.index,
+                    })
+                    .collect();
+                return Ok(Answers {
+                    choices,
+                    tool_messages,
+                    cache: response.cache,
+                    usage,
+                });
+            }
+
+            // The model wants to use tools.  Run them and send the results
+            self.api.keep(&response.cache);
+            let assistant = response.choices[0].message.clone();
+            messages.push(assistant.clone());
+            tool_messages.push(assistant);
+            for call in calls.iter() {
+                println!(
+                    "\x1b[1;36mTool call: {}({})\x1b[0m",
+                    call.function.name, call.function.arguments
+                );
+                let builtin = self.workspace.as_ref().filter(|w| {
+                    w.handles(&call.function.name)
+                        && !self.tools.iter().any(|t| t.name == call.function.name)
+                });
+                let output = match builtin {
+                    Some(workspace) => match serde_json::from_str(&call.function.arguments) {
+                        Ok(arguments) => workspace.call(&call.function.name, &arguments, confirm),
+                        Err(err) => format!("Arguments are not JSON: {err}"),
+                    },
+                    None => self.run_tool(call, confirm),
+                };
+                println!("{output}");
+                let message = ChatMessage::tool(&call.id, &output);
+                messages.push(message.clone());
+                tool_messages.push(message);
+            }
+        }
+        Err(format!(
+            "No answer after {MAX_TOOL_ROUNDS} rounds of tool calls"
+        ))
+    }
+
+    /// Run a tool from the configuration
+    fn run_tool(&self, call: &ToolCall, confirm: &mut dyn FnMut(&str) -> bool) -> String {
+        match tools::prepare(&self.tools, call) {
+            Ok(prepared) => {
+                if prepared.tool.auto_approve || confirm("Run it? [y/N] ") {
+                    prepared
+                        .tool
+                        .run(&prepared.arguments)
+                        .unwrap_or_else(|err| err)
+                } else {
+                    "The user declined to run the tool".to_string()
+                }
+            }
+            Err(err) => err,
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! The Batch API: requests sent as a file, answered within a day at
+//! half the price.  See
+//! https://platform.openai.com/docs/api-reference/batch
+use crate::api_client::ApiClient;
+use crate::files;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Seconds between checks on a batch
+const POLL_SECONDS: u64 = 30;
+
+/// Batches in these states will not change
+const FINISHED: [&str; 4] = ["completed", "failed", "expired", "cancelled"];
+
+#[derive(Debug, Serialize)]
+struct CreateBatch<'a> {
+    input_file_id: &'a str,
+    endpoint: &'a str,
+    completion_window: &'a str,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Batch {
+    pub id: String,
+    pub endpoint: String,
+    pub status: String,
+    pub input_file_id: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    #[serde(default)]
+    pub request_counts: RequestCounts,
+}
+
+impl Batch {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} ({}): {} of {} done, {} failed",
+            self.id,
+            self.status,
+            self.endpoint,
+            self.request_counts.completed,
+            self.request_counts.total,
+            self.request_counts.failed
+        )
+    }
+}
+
+/// Check that each line of `input` is a request with a unique
+/// `custom_id`, all for the same end point, and return the end point
+pub fn check_input(input: &str) -> Result<String, String> {
+    let mut ids = HashSet::new();
+    let mut endpoint: Option<String> = None;
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {message}", i + 1);
+        let value: Value = serde_json::from_str(line).map_err(|err| error(&err.to_string()))?;
+        let id = value["custom_id"]
+            .as_str()
+            .ok_or_else(|| error("no `custom_id`"))?;
+        if !ids.insert(id.to_string()) {
+            return Err(error(&format!("`custom_id` {id} is used already")));
+        }
+        if value["method"] != "POST" || !value["body"].is_object() {
+            return Err(error("needs `\"method\": \"POST\"` and a `body`"));
+        }
+        let url = value["url"].as_str().ok_or_else(|| error("no `url`"))?;
+        match endpoint.as_deref() {
+            None => endpoint = Some(url.to_string()),
+            Some(endpoint) if endpoint != url => {
+                return Err(error(&format!("`url` is not {endpoint}")));
+            }
+            Some(_) => (),
+        }
+    }
+    endpoint.ok_or_else(|| "There are no requests".to_string())
+}
+
+/// Upload `path` and start a batch for it
+pub fn submit(api: &ApiClient, path: &Path) -> Result<Batch, String> {
+    let input = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let endpoint = check_input(&input).map_err(|err| format!("{}: {err}", path.display()))?;
+    let file = files::upload(api, path, "batch")?;
+    let request = CreateBatch {
+        input_file_id: &file.id,
+        endpoint: &endpoint,
+        completion_window: "24h",
+    };
+    api.post_json("/batches", &request)
+        .map_err(|e| e.to_string())
+}
+
+pub fn retrieve(api: &ApiClient, id: &str) -> Result<Batch, String> {
+    api.get_json(&format!("/batches/{id}"))
+        .map_err(|e| e.to_string())
+}
+
+/// Each line of `input`, in order, with its response from `output` or
+/// its error from `errors`, joined by `custom_id`.  Also how many had
+/// no result
+pub fn join(input: &str, output: &str, errors: &str) -> Result<(Vec<Value>, usize), String> {
+    let mut results: HashMap<String, Value> = HashMap::new();
+    for line in output.lines().chain(errors.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(line).map_err(|err| format!("Bad result: {err}"))?;
+        if let Some(id) = value["custom_id"].as_str() {
+            results.insert(id.to_string(), value);
+        }
+    }
+    let mut merged = Vec::new();
+    let mut missing = 0;
+    for line in input.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Value =
+            serde_json::from_str(line).map_err(|err| format!("Bad input: {err}"))?;
+        let id = request["custom_id"].as_str().unwrap_or_default();
+        let result = results.remove(id).unwrap_or(Value::Null);
+        if result.is_null() {
+            missing += 1;
+        }
+        merged.push(json!({
+            "custom_id": id,
+            "request": request["body"],
+            "status_code": result["response"]["status_code"],
+            "response": result["response"]["body"],
+            "error": result["error"],
+        }));
+    }
+    Ok((merged, missing))
+}
+
+/// For `batch submit`
+pub fn run_submit(api: &ApiClient, path: &Path) -> Result<(), String> {
+    let batch = submit(api, path)?;
+    println!("{}", batch.describe());
+    println!("`batch status {}` to follow it", batch.id);
+    Ok(())
+}
+
+/// For `batch status`.  Checks the batch until it finishes, unless
+/// `follow` is false
+pub fn run_status(api: &ApiClient, id: &str, follow: bool) -> Result<(), String> {
+    loop {
+        let batch = retrieve(api, id)?;
+        println!("{}", batch.describe());
+        if !follow || FINISHED.contains(&batch.status.as_str()) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(POLL_SECONDS));
+    }
+}
+
+/// For `batch fetch`.  Downloads the results and writes them, with
+/// the requests, to `output`, by default "<id>-results.jsonl".  The
+/// requests are read from `input`, or downloaded if it is not given
+pub fn run_fetch(
+    api: &ApiClient,
+    id: &str,
+    input: Option<&Path>,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let batch = retrieve(api, id)?;
+    if batch.output_file_id.is_none() && batch.error_file_id.is_none() {
+        return Err(format!("There are no results yet.  {}", batch.describe()));
+    }
+    let text = |file_id: Option<&str>| -> Result<String, String> {
+        match file_id {
+            Some(file_id) => {
+                Ok(String::from_utf8_lossy(&files::download(api, file_id)?).to_string())
+            }
+            None => Ok(String::new()),
+        }
+    };
+    let requests = match input {
+        Some(input) => {
+            fs::read_to_string(input).map_err(|err| format!("{}: {err}", input.display()))?
+        }
+        None => text(Some(&batch.input_file_id))?,
+    };
+    let (merged, missing) = join(
+        &requests,
+        &text(batch.output_file_id.as_deref())?,
+        &text(batch.error_file_id.as_deref())?,
+    )?;
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(format!("{id}-results.jsonl")));
+    let lines: Vec<String> = merged.iter().map(Value::to_string).collect();
+    fs::write(&output, lines.join("\n") + "\n")
+        .map_err(|err| format!("{}: {err}", output.display()))?;
+    let failed = merged
+        .iter()
+        .filter(|m| !m["error"].is_null() || m["status_code"].as_u64().is_some_and(|s| s != 200))
+        .count();
+    println!(
+        "Saved {} results in {}: {failed} failed, {missing} with no result",
+        merged.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn check_and_join() {
+        let input = [
+            r#"{"custom_id": "a", "method": "POST", "url": "/v1/chat/completions", "body": {"n": 1}}"#,
+            r#"{"custom_id": "b", "method": "POST", "url": "/v1/chat/completions", "body": {"n": 2}}"#,
+            r#"{"custom_id": "c", "method": "POST", "url": "/v1/chat/completions", "body": {"n": 3}}"#,
+        ]
+        .join("\n");
+        assert_eq!(check_input(&input).unwrap(), "/v1/chat/completions");
+        let duplicate = format!("{input}\n{}", input.lines().next().unwrap());
+        assert!(check_input(&duplicate).unwrap_err().contains("line 4"));
+
+        // Results come back in any order
+        let output = [
+            r#"{"custom_id": "b", "response": {"status_code": 200, "body": {"answer": 2}}, "error": null}"#,
+            r#"{"custom_id": "a", "response": {"status_code": 200, "body": {"answer": 1}}, "error": null}"#,
+        ]
+        .join("\n");
+        let (merged, missing) = join(&input, &output, "").unwrap();
+        assert_eq!(missing, 1);
+        assert_eq!(merged[0]["request"]["n"], 1);
+        assert_eq!(merged[0]["response"]["answer"], 1);
+        assert_eq!(merged[1]["response"]["answer"], 2);
+        assert!(merged[2]["response"].is_null());
+    }
+}
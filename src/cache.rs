@@ -0,0 +1,190 @@
+//! An on-disk cache of answers, so asking the same thing again, with
+//! the same model and parameters, costs nothing.  Each response is a
+//! file in the cache directory named after a hash of the request.
+//! Entries older than the TTL are ignored, and the oldest are removed
+//! when the cache grows past its size limit
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// The `cache` section of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    /// How long an answer is good for
+    pub ttl_hours: u64,
+    /// The most space the cache may use
+    pub max_mb: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(".open_ai_cache"),
+            ttl_hours: 24,
+            max_mb: 100,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// For `> cache stats`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: u64,
+    /// In this session
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<Self, String> {
+        fs::create_dir_all(&config.dir)
+            .map_err(|err| format!("{}: {err}", config.dir.display()))?;
+        Ok(Self {
+            dir: config.dir.clone(),
+            ttl: Duration::from_secs(config.ttl_hours * 3600),
+            max_bytes: config.max_mb * 1024 * 1024,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// The response cached for `key`, unless it has expired
+    pub fn get(&self, key: &str) -> Option<String> {
+        let path = self.path(key);
+        let fresh = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age <= self.ttl);
+        let text = if fresh {
+            fs::read_to_string(&path).ok()
+        } else {
+            _ = fs::remove_file(&path);
+            None
+        };
+        if text.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        text
+    }
+
+    /// Cache `response` for `key`, then make room if the cache is too big
+    pub fn put(&self, key: &str, response: &str) -> Result<(), String> {
+        let path = self.path(key);
+        fs::write(&path, response).map_err(|err| format!("{}: {err}", path.display()))?;
+        self.prune();
+        Ok(())
+    }
+
+    /// The entries, oldest first, with their sizes
+    fn entries(&self) -> Vec<(PathBuf, SystemTime, u64)> {
+        let mut entries: Vec<(PathBuf, SystemTime, u64)> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|e| e.path().extension().is_some_and(|x| x == "json"))
+            .filter_map(|e| {
+                let metadata = e.metadata().ok()?;
+                Some((e.path(), metadata.modified().ok()?, metadata.len()))
+            })
+            .collect();
+        entries.sort_by_key(|(_, modified, _)| *modified);
+        entries
+    }
+
+    /// Remove the oldest entries until the cache fits
+    fn prune(&self) {
+        let entries = self.entries();
+        let mut total: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        for (path, _, size) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= size;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let entries = self.entries();
+        Stats {
+            entries: entries.len(),
+            bytes: entries.iter().map(|(_, _, size)| size).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Remove every entry.  Returns how many there were
+    pub fn clear(&self) -> usize {
+        self.entries()
+            .iter()
+            .filter(|(path, _, _)| fs::remove_file(path).is_ok())
+            .count()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn get_put_prune() {
+        let dir = std::env::temp_dir().join(format!("cache_test_{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        let config = CacheConfig {
+            dir: dir.clone(),
+            ttl_hours: 1,
+            max_mb: 1,
+        };
+        let cache = ResponseCache::new(&config).unwrap();
+        assert_eq!(cache.get("a"), None);
+        cache.put("a", "answer").unwrap();
+        assert_eq!(cache.get("a").as_deref(), Some("answer"));
+
+        // Too big: the oldest go
+        let big = "x".repeat(700 * 1024);
+        std::thread::sleep(Duration::from_millis(20));
+        cache.put("b", &big).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        cache.put("c", &big).unwrap();
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("c").is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 2, 2));
+
+        let expired = ResponseCache::new(&CacheConfig {
+            ttl_hours: 0,
+            ..config
+        })
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(expired.get("c"), None);
+        assert_eq!(cache.clear(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
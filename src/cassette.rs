@@ -0,0 +1,207 @@
+//! Recording the requests sent to the API, and the responses, so a
+//! session can be replayed without the network.  Each exchange is a
+//! JSON file in the cassette directory named after a hash of the
+//! request: method, path and body, with the keys of JSON bodies in
+//! order.  The same request made again in a session is numbered, so a
+//! replay gives the answers in the order they were recorded.  The API
+//! key is never recorded
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Replaces the API key wherever it appears
+const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+/// One request and its response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    pub path: String,
+    /// JSON bodies are stored as JSON, other text as a string
+    pub request: Value,
+    pub status: u16,
+    /// The response as text, if it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    /// Or base64 encoded, if it is not (audio, images...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_base64: Option<String>,
+}
+
+impl Exchange {
+    pub fn body(&self) -> Result<Vec<u8>, String> {
+        match (self.response.as_ref(), self.response_base64.as_ref()) {
+            (Some(text), _) => Ok(text.as_bytes().to_vec()),
+            (None, Some(encoded)) => base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|err| format!("Bad recording: {err}")),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cassette {
+    pub dir: PathBuf,
+    pub mode: Mode,
+    /// How many times each request has been seen in this session
+    seen: Mutex<HashMap<String, usize>>,
+}
+
+impl Cassette {
+    pub fn new(dir: &Path, mode: Mode) -> Result<Self, String> {
+        match mode {
+            Mode::Record => {
+                fs::create_dir_all(dir).map_err(|err| format!("{}: {err}", dir.display()))?
+            }
+            Mode::Replay if !dir.is_dir() => {
+                return Err(format!("{}: no such cassette", dir.display()))
+            }
+            Mode::Replay => (),
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            mode,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The file for this request.  Each call for the same request gives
+    /// the next file
+    fn file(&self, method: &str, path: &str, body: &[u8]) -> PathBuf {
+        let key = key(method, path, body);
+        let mut seen = self.seen.lock().unwrap();
+        let count = seen.entry(key.clone()).or_insert(0);
+        *count += 1;
+        self.dir.join(format!("{key}-{count}.json"))
+    }
+
+    /// The recorded response to this request
+    pub fn replay(&self, method: &str, path: &str, body: &[u8]) -> Result<Exchange, String> {
+        let file = self.file(method, path, body);
+        let text = fs::read_to_string(&file).map_err(|_| {
+            format!(
+                "Replay: nothing recorded for {method} {path} ({}).  The request was:\n{}",
+                file.display(),
+                String::from_utf8_lossy(body)
+            )
+        })?;
+        serde_json::from_str(&text).map_err(|err| format!("{}: {err}", file.display()))
+    }
+
+    /// Save a request and its response.  `api_key` is removed
+    pub fn record(
+        &self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        status: u16,
+        response: &[u8],
+        api_key: &str,
+    ) -> Result<(), String> {
+        let file = self.file(method, path, body);
+        let redact = |text: &str| {
+            if api_key.is_empty() {
+                text.to_string()
+            } else {
+                text.replace(api_key, REDACTED)
+            }
+        };
+        let request = String::from_utf8_lossy(body);
+        let (response, response_base64) = match std::str::from_utf8(response) {
+            Ok(text) => (Some(redact(text)), None),
+            Err(_) => (
+                None,
+                Some(base64::engine::general_purpose::STANDARD.encode(response)),
+            ),
+        };
+        let exchange = Exchange {
+            method: method.to_string(),
+            path: redact(path),
+            request: serde_json::from_str(&redact(&request))
+                .unwrap_or_else(|_| Value::String(redact(&request))),
+            status,
+            response,
+            response_base64,
+        };
+        fs::write(&file, serde_json::to_string_pretty(&exchange).unwrap())
+            .map_err(|err| format!("{}: {err}", file.display()))
+    }
+}
+
+/// The hash that names a request's files.  JSON bodies are compared as
+/// JSON, so the order of keys and spacing do not matter
+pub fn key(method: &str, path: &str, body: &[u8]) -> String {
+    let body = match serde_json::from_slice::<Value>(body) {
+        // `Value` keeps object keys in order
+        Ok(value) => value.to_string().into_bytes(),
+        Err(_) => body.to_vec(),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(method.to_uppercase().as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn record_and_replay() {
+        assert_eq!(
+            key("POST", "/chat/completions", br#"{"a": 1, "b": [2]}"#),
+            key("post", "/chat/completions", br#"{"b":[2],"a":1}"#)
+        );
+        assert_ne!(
+            key("POST", "/chat/completions", b"{}"),
+            key("POST", "/completions", b"{}")
+        );
+
+        let dir = std::env::temp_dir().join(format!("cassette_test_{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        let recorder = Cassette::new(&dir, Mode::Record).unwrap();
+        let body = br#"{"user": "sk-secret"}"#;
+        recorder
+            .record("POST", "/x", body, 200, b"first sk-secret", "sk-secret")
+            .unwrap();
+        recorder
+            .record("POST", "/x", body, 500, &[0xff, 0x00], "sk-secret")
+            .unwrap();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let text = fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!text.contains("sk-secret"));
+        }
+
+        let player = Cassette::new(&dir, Mode::Replay).unwrap();
+        let first = player
+            .replay("POST", "/x", br#"{"user":"sk-secret"}"#)
+            .unwrap();
+        assert_eq!(first.body().unwrap(), b"first REDACTED");
+        let second = player.replay("POST", "/x", body).unwrap();
+        assert_eq!(
+            (second.status, second.body().unwrap()),
+            (500, vec![0xff, 0x00])
+        );
+        assert!(player.replay("POST", "/x", body).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
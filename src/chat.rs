@@ -0,0 +1,317 @@
+//! The chat completions end point.  See
+//! https://platform.openai.com/docs/api-reference/chat/create
+use crate::api_client::{ApiClient, ApiError, CacheStatus};
+use crate::logprobs::LogProbs;
+use crate::sampling::Sampling;
+use crate::tools::ToolSpec;
+use crate::usage::Usage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// "system", "user", "assistant" or "tool"
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<Content>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// For "tool" messages, the call this is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: Some(Content::Text(content.to_string())),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn user(content: &str) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Self::new("assistant", content)
+    }
+
+    /// A user message with images as well as text
+    pub fn user_with_images(content: &str, images: &[ImageUrl]) -> Self {
+        if images.is_empty() {
+            return Self::user(content);
+        }
+        let mut parts = vec![ContentPart::Text {
+            text: content.to_string(),
+        }];
+        parts.extend(images.iter().map(|image_url| ContentPart::ImageUrl {
+            image_url: image_url.clone(),
+        }));
+        Self {
+            content: Some(Content::Parts(parts)),
+            ..Self::user("")
+        }
+    }
+
+    /// The text of the message, without any images
+    pub fn text(&self) -> String {
+        match self.content.as_ref() {
+            None => String::new(),
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    /// The output of the tool call `tool_call_id`
+    pub fn tool(tool_call_id: &str, content: &str) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Self::new("tool", content)
+        }
+    }
+}
+
+/// Either plain text or, for messages with images, a list of parts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// A web address, or a `data:` URL with the image base64 encoded
+    pub url: String,
+    /// "low", "high" or "auto"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON, as generated by the model.  It may not be valid
+    pub arguments: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub messages: &'a [ChatMessage],
+    pub temperature: f32,
+    pub max_tokens: u32,
+    #[serde(skip_serializing_if = "is_one")]
+    pub n: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "<[ToolSpec]>::is_empty")]
+    pub tools: &'a [ToolSpec],
+    /// "none" stops the model calling tools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<&'static str>,
+    #[serde(flatten)]
+    pub sampling: &'a Sampling,
+    /// Ask the API, even if the answer is cached
+    #[serde(skip)]
+    pub fresh: bool,
+}
+
+fn is_one(n: &u32) -> bool {
+    *n == 1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatResponse {
+    pub choices: Vec<ChatChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Where the response came from
+    #[serde(skip)]
+    pub cache: CacheStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatChoice {
+    pub index: i32,
+    pub message: ChatMessage,
+    pub finish_reason: Option<String>,
+    pub logprobs: Option<ChatLogProbs>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatLogProbs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogProb>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenLogProb {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogProb>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopLogProb {
+    pub token: String,
+    pub logprob: f32,
+}
+
+impl ChatLogProbs {
+    /// In the form the completions end point uses
+    pub fn to_logprobs(&self) -> LogProbs {
+        let content = self.content.as_deref().unwrap_or_default();
+        LogProbs {
+            tokens: content.iter().map(|t| t.token.clone()).collect(),
+            token_logprobs: content.iter().map(|t| Some(t.logprob)).collect(),
+            top_logprobs: content
+                .iter()
+                .map(|t| {
+                    Some(
+                        t.top_logprobs
+                            .iter()
+                            .map(|top| (top.token.clone(), top.logprob))
+                            .collect::<HashMap<String, f32>>(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Models that use the chat completions end point, rather than the
+/// (legacy) completions end point
+pub fn is_chat_model(model: &str) -> bool {
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    if model.contains("instruct") {
+        return false;
+    }
+    model.starts_with("gpt-") || model.starts_with("chatgpt-") || is_reasoning_model(model)
+}
+
+/// Reasoning models, o1, o3..., have their own parameters
+pub fn is_reasoning_model(model: &str) -> bool {
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// Parameters reasoning models reject, so are not sent to them
+const REASONING_UNSUPPORTED: [&str; 7] = [
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "logprobs",
+    "top_logprobs",
+    "logit_bias",
+];
+
+impl ChatRequest<'_> {
+    /// The request body.  Reasoning models take `max_completion_tokens`
+    /// instead of `max_tokens`, and reject `REASONING_UNSUPPORTED`
+    pub fn body(&self) -> Value {
+        let mut body = serde_json::to_value(self).expect("A request is JSON");
+        if is_reasoning_model(self.model) {
+            if let Some(object) = body.as_object_mut() {
+                for name in REASONING_UNSUPPORTED {
+                    object.remove(name);
+                }
+                if let Some(max_tokens) = object.remove("max_tokens") {
+                    object.insert("max_completion_tokens".to_string(), max_tokens);
+                }
+            }
+        }
+        body
+    }
+}
+
+pub fn complete(api: &ApiClient, request: &ChatRequest) -> Result<ChatResponse, ApiError> {
+    let (mut response, cache): (ChatResponse, CacheStatus) =
+        api.post_json_cached("/chat/completions", &request.body(), request.fresh)?;
+    response.cache = cache;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn chat_models() {
+        assert!(is_chat_model("gpt-4o-mini"));
+        assert!(is_chat_model("o3-mini"));
+        assert!(is_chat_model("ft:gpt-4o-mini-2024-07-18:org::abc"));
+        assert!(!is_chat_model("gpt-3.5-turbo-instruct"));
+        assert!(!is_chat_model("text-davinci-003"));
+        assert!(!is_chat_model("omni-moderation-latest"));
+    }
+
+    #[test]
+    fn reasoning_model_body() {
+        let messages = [ChatMessage::user("Hi")];
+        let sampling = Sampling {
+            top_p: Some(0.5),
+            presence_penalty: Some(0.5),
+            frequency_penalty: Some(0.5),
+            logit_bias: Some([("50256".to_string(), -100)].into()),
+            seed: Some(1),
+            ..Sampling::default()
+        };
+        let request = |model| ChatRequest {
+            model,
+            messages: &messages,
+            temperature: 0.5,
+            max_tokens: 100,
+            n: 1,
+            logprobs: Some(true),
+            top_logprobs: Some(2),
+            tools: &[],
+            tool_choice: None,
+            sampling: &sampling,
+            fresh: false,
+        };
+        let body = request("o3-mini").body();
+        assert_eq!(body["max_completion_tokens"], 100);
+        assert_eq!(body["seed"], 1);
+        assert!(body.get("max_tokens").is_none());
+        for name in REASONING_UNSUPPORTED {
+            assert!(body.get(name).is_none(), "{name}: {body}");
+        }
+        let body = request("gpt-4o").body();
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["top_p"], 0.5);
+        assert_eq!(body["top_logprobs"], 2);
+        assert!(body.get("max_completion_tokens").is_none());
+    }
+}
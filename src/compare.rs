@@ -0,0 +1,290 @@
+//! Asking several models the same thing at once, and displaying their
+//! answers side by side with how long each took, the tokens it used and
+//! what they cost.  Columns if the terminal is wide enough, otherwise
+//! one panel after another
+use crate::backend::{Answers, Backend};
+use crate::chat::ImageUrl;
+use crate::completions::CompletionRequestInfo;
+use crate::conversation::Conversation;
+use crate::usage::{self, Usage};
+use serde::Serialize;
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Narrower columns than this are hard to read, so panels are used
+const MIN_COLUMN: usize = 30;
+
+/// Between columns
+const SEPARATOR: &str = " │ ";
+
+/// One model's answer
+pub struct Outcome {
+    pub model: String,
+    pub answers: Result<Answers, String>,
+    pub latency: Duration,
+}
+
+/// An outcome, for the session log and reports
+#[derive(Debug, Serialize)]
+pub struct Summary<'a> {
+    pub model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    pub latency_ms: u128,
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+impl Outcome {
+    /// The first answer, if there is one
+    pub fn answer(&self) -> Option<&str> {
+        let answers = self.answers.as_ref().ok()?;
+        answers.choices.first().map(|c| c.text.trim())
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.answers
+            .as_ref()
+            .map(|answers| answers.usage)
+            .unwrap_or_default()
+    }
+
+    pub fn cost(&self) -> Option<f64> {
+        usage::cost(&self.model, &self.usage())
+    }
+
+    /// Latency, tokens in and out, and cost
+    pub fn describe(&self) -> String {
+        let usage = self.usage();
+        format!(
+            "{:.1}s, {}+{} tokens, {}",
+            self.latency.as_secs_f64(),
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage::format_cost(self.cost())
+        )
+    }
+
+    /// The answer, or what went wrong
+    fn text(&self) -> String {
+        match (&self.answers, self.answer()) {
+            (Err(err), _) => format!("Error: {err}"),
+            (Ok(_), Some(answer)) => answer.to_string(),
+            (Ok(_), None) => "No answer".to_string(),
+        }
+    }
+
+    pub fn summary(&self) -> Summary<'_> {
+        Summary {
+            model: &self.model,
+            answer: self.answer(),
+            error: self.answers.as_ref().err().map(String::as_str),
+            latency_ms: self.latency.as_millis(),
+            usage: self.usage(),
+            cost: self.cost(),
+        }
+    }
+}
+
+/// Ask each of `models` `prompt`, with `conversation` as context, at the
+/// same time.  The other parameters are those in `request_info`.  Tools
+/// that need approval are not run
+pub fn ask_all(
+    backend: &Backend,
+    request_info: &CompletionRequestInfo,
+    models: &[String],
+    conversation: &Conversation,
+    prompt: &str,
+    images: &[ImageUrl],
+) -> Vec<Outcome> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = models
+            .iter()
+            .map(|model| {
+                let mut request_info = request_info.clone();
+                request_info.model = model.clone();
+                request_info.n = 1;
+                scope.spawn(move || {
+                    let started = Instant::now();
+                    let answers = backend.ask(
+                        &request_info,
+                        conversation,
+                        prompt,
+                        images,
+                        None,
+                        &mut |_| false,
+                    );
+                    if let Ok(answers) = answers.as_ref() {
+                        backend.api.keep(&answers.cache);
+                    }
+                    Outcome {
+                        model: request_info.model,
+                        answers,
+                        latency: started.elapsed(),
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("A request panicked"))
+            .collect()
+    })
+}
+
+/// How wide the terminal is: $COLUMNS, or 80
+pub fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// `text` in lines no wider than `width`
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            // Words too long for a line are split
+            while word.len() > width {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.drain(..width).collect());
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let length = line.chars().count();
+            if length > 0 && length + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+            } else if length > 0 {
+                line.push(' ');
+            }
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// The outcomes, numbered, in columns if each can be at least
+/// `MIN_COLUMN` wide in `width`, or in panels
+pub fn layout(outcomes: &[Outcome], width: usize) -> String {
+    let count = outcomes.len().max(1);
+    let column = width.saturating_sub(SEPARATOR.chars().count() * (count - 1)) / count;
+    let mut result = String::new();
+    if column >= MIN_COLUMN {
+        let cells: Vec<Vec<String>> = outcomes
+            .iter()
+            .enumerate()
+            .map(|(i, outcome)| {
+                let mut cell = wrap(&format!("[{}] {}", i + 1, outcome.model), column);
+                cell.extend(wrap(&outcome.describe(), column));
+                cell.push("─".repeat(column));
+                cell.extend(wrap(&outcome.text(), column));
+                cell
+            })
+            .collect();
+        let rows = cells.iter().map(Vec::len).max().unwrap_or(0);
+        for row in 0..rows {
+            let line: Vec<String> = cells
+                .iter()
+                .map(|cell| {
+                    let text = cell.get(row).map(String::as_str).unwrap_or_default();
+                    format!("{text}{}", " ".repeat(column - text.chars().count()))
+                })
+                .collect();
+            result.push_str(line.join(SEPARATOR).trim_end());
+            result.push('\n');
+        }
+    } else {
+        for (i, outcome) in outcomes.iter().enumerate() {
+            let title = format!("── [{}] {} ", i + 1, outcome.model);
+            let rule = width.saturating_sub(title.chars().count());
+            result.push_str(&format!("{title}{}\n", "─".repeat(rule)));
+            result.push_str(&format!("{}\n", outcome.describe()));
+            for line in wrap(&outcome.text(), width) {
+                result.push_str(&format!("{line}\n"));
+            }
+        }
+    }
+    result
+}
+
+/// For `compare`.  Ask each of `models` `prompt` and display the answers
+pub fn run_compare(
+    backend: &Backend,
+    request_info: &CompletionRequestInfo,
+    prompt: &str,
+    models: &[String],
+) -> Result<(), String> {
+    let outcomes = ask_all(
+        backend,
+        request_info,
+        models,
+        &Conversation::new(),
+        prompt,
+        &[],
+    );
+    print!("{}", layout(&outcomes, terminal_width()));
+    if outcomes.iter().all(|outcome| outcome.answers.is_err()) {
+        return Err("No model answered".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::CacheStatus;
+    use crate::completions::Choice;
+    #[test]
+    fn layouts() {
+        assert_eq!(
+            wrap("one two three\n\nfour", 8),
+            ["one two", "three", "", "four"]
+        );
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+
+        let outcome = |model: &str, answer: Result<&str, &str>| Outcome {
+            model: model.to_string(),
+            answers: answer.map_err(str::to_string).map(|text| Answers {
+                choices: vec![Choice {
+                    text: text.to_string(),
+                    logprobs: None,
+                    finish_reason: None,
+                    index: 0,
+                }],
+                tool_messages: Vec::new(),
+                cache: CacheStatus::Uncached,
+                usage: Usage {
+                    prompt_tokens: 1000,
+                    completion_tokens: 100,
+                    total_tokens: 1100,
+                },
+            }),
+            latency: Duration::from_millis(1500),
+        };
+        let outcomes = [
+            outcome("gpt-4o-mini", Ok("Paris is the capital of France.")),
+            outcome("other", Err("Timed out")),
+        ];
+        let columns = layout(&outcomes, 80);
+        let lines: Vec<&str> = columns.lines().collect();
+        assert!(lines[0].starts_with("[1] gpt-4o-mini") && lines[0].contains("│ [2] other"));
+        assert!(lines[1].starts_with("1.5s, 1000+100 tokens, $0.000210"));
+        assert!(lines[1].ends_with("unknown"));
+        assert!(lines[3].starts_with("Paris is the capital of") && lines[3].contains("Error"));
+
+        let panels = layout(&outcomes, 40);
+        assert!(panels.starts_with("── [1] gpt-4o-mini ──"));
+        assert!(panels.contains("\n── [2] other ──"));
+        assert!(panels.ends_with("Error: Timed out\n"));
+    }
+}
@@ -0,0 +1,128 @@
+//! The (legacy) completions end point.  See
+//! https://platform.openai.com/docs/api-reference/completions/create
+use crate::api_client::{ApiClient, ApiError, CacheStatus};
+use crate::logprobs::LogProbs;
+use crate::sampling::Sampling;
+use crate::usage::Usage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Request, and response, for a completions request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionRequestInfo {
+    #[serde(skip_serializing)]
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub object: String,
+    #[serde(skip_serializing)]
+    pub choices: Vec<Choice>,
+    #[serde(skip_deserializing)]
+    pub prompt: String,
+    pub model: String,
+    #[serde(skip_deserializing)]
+    pub temperature: f32,
+    #[serde(skip_deserializing)]
+    pub max_tokens: u32,
+    /// The number of choices to generate
+    #[serde(skip_deserializing, skip_serializing_if = "is_one")]
+    pub n: u32,
+    /// Return the log probabilities of this many most likely tokens
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u32>,
+    #[serde(skip_deserializing, flatten)]
+    pub sampling: Sampling,
+    /// Tokens used, in the response
+    #[serde(skip_serializing, default)]
+    pub usage: Option<Usage>,
+    /// Where the response came from
+    #[serde(skip)]
+    pub cache: CacheStatus,
+    /// Ask the API, even if the answer is cached
+    #[serde(skip)]
+    pub fresh: bool,
+}
+impl CompletionRequestInfo {
+    pub fn new(prompt: String, model: String, temperature: f32, max_tokens: u32) -> Self {
+        Self {
+            choices: Vec::new(),
+            id: String::new(),
+            object: String::new(),
+            prompt,
+            model,
+            temperature,
+            max_tokens,
+            n: 1,
+            logprobs: None,
+            sampling: Sampling::default(),
+            usage: None,
+            cache: CacheStatus::Uncached,
+            fresh: false,
+        }
+    }
+}
+
+impl CompletionRequestInfo {
+    /// The request body.  The completions end point has no structured
+    /// outputs, so `response_format` is left out
+    pub fn body(&self) -> Value {
+        let mut body = serde_json::to_value(self).expect("A request is JSON");
+        if let Some(object) = body.as_object_mut() {
+            object.remove("response_format");
+        }
+        body
+    }
+}
+
+fn is_one(n: &u32) -> bool {
+    *n == 1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Choice {
+    pub text: String,
+    pub logprobs: Option<LogProbs>,
+    /// "stop" if the model finished, "length" if it ran out of tokens
+    pub finish_reason: Option<String>,
+    pub index: i32,
+}
+
+/// Send a completions request and return the response
+pub fn complete(
+    api: &ApiClient,
+    request_info: &CompletionRequestInfo,
+) -> Result<CompletionRequestInfo, ApiError> {
+    let (mut response, cache): (CompletionRequestInfo, CacheStatus) =
+        api.post_json_cached("/completions", &request_info.body(), request_info.fresh)?;
+    response.cache = cache;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_key::ApiKey;
+    use crate::json_schema::JsonSchemaFormat;
+    use crate::mock_server::MockServer;
+    use crate::sampling::ResponseFormat;
+    use reqwest::blocking::Client;
+
+    #[test]
+    fn no_response_format() {
+        let server = MockServer::start(0, Vec::new()).unwrap();
+        let api = ApiClient::new(Client::new(), ApiKey::new("sk-test")).with_base_url(&server.url);
+        let mut request =
+            CompletionRequestInfo::new("Q: Hi\nA:".to_string(), "davinci".to_string(), 0.5, 100);
+        request.sampling.top_p = Some(0.5);
+        request.sampling.response_format = Some(ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: "answer".to_string(),
+                schema: serde_json::json!({"type": "object"}),
+                strict: None,
+            },
+        });
+        complete(&api, &request).unwrap();
+        let body = server.requests()[0].json();
+        assert_eq!(body["top_p"], 0.5);
+        assert!(body.get("response_format").is_none(), "{body}");
+    }
+}
@@ -0,0 +1,80 @@
+//! The configuration file.  JSON, by default `config.json` in the
+//! current directory.  Everything in it is optional, and command line
+//! options take precedence.  For example:
+//!
+//! ```json
+//! {
+//!     "top_p": 0.9,
+//!     "stop": ["\nQ:"],
+//!     "user": "worik"
+//! }
+//! ```
+use crate::api_key::KeyConfig;
+use crate::cache::CacheConfig;
+use crate::moderation::ModerationConfig;
+use crate::network::NetworkConfig;
+use crate::profiles::{self, Profile};
+use crate::sampling::Sampling;
+use crate::tools::ToolConfig;
+use crate::usage::UsageConfig;
+use crate::workspace::WorkspaceConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Sampling parameters are at the top level
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    /// Local commands the model can call.  See `tools`
+    #[serde(default)]
+    pub tools: Vec<ToolConfig>,
+    /// Enables the built in tools.  See `workspace`
+    #[serde(default)]
+    pub workspace: Option<WorkspaceConfig>,
+    /// Enables checking prompts and answers.  See `moderation`
+    #[serde(default)]
+    pub moderation: Option<ModerationConfig>,
+    /// Enables the response cache.  See `cache`
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// Where to find the API key.  See `api_key`
+    #[serde(default)]
+    pub api_key: KeyConfig,
+    /// Other keys, with organizations and projects.  See `profiles`
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// The proxy, certificates and timeout.  See `network`
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// Where the tokens used are recorded.  See `usage`
+    #[serde(default)]
+    pub usage: UsageConfig,
+}
+
+impl Config {
+    /// Read the configuration at `path`.  A missing file is an empty
+    /// configuration, but one that cannot be parsed is an error
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(format!("{}: {err}", path.display())),
+        };
+        let config: Config =
+            serde_json::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+        config
+            .sampling
+            .validate()
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        if config.profiles.contains_key(profiles::DEFAULT) {
+            return Err(format!(
+                "{}: a profile cannot be called {}, the key used without --profile",
+                path.display(),
+                profiles::DEFAULT
+            ));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,126 @@
+//! The conversation so far.  Each turn is a prompt and the answer it
+//! got.  The turns are sent back to the model as context.
+use crate::chat::{ChatMessage, ImageUrl};
+
+/// Sent after the start of an answer to ask a chat model for the rest
+const CONTINUE: &str = "Continue from exactly where you stopped.";
+
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub prompt: String,
+    pub answer: String,
+    /// Tool calls, and their results, made while answering `prompt`
+    pub tool_messages: Vec<ChatMessage>,
+    /// Images sent with `prompt`
+    pub images: Vec<ImageUrl>,
+}
+
+impl Turn {
+    pub fn new(prompt: String, answer: String) -> Self {
+        Self {
+            prompt,
+            answer,
+            tool_messages: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Conversation {
+    turns: Vec<Turn>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, prompt: String, answer: String) {
+        self.push_turn(Turn::new(prompt, answer));
+    }
+
+    pub fn push_turn(&mut self, turn: Turn) {
+        self.turns.push(turn);
+    }
+
+    /// Remove the last turn from the context, returning it
+    pub fn pop(&mut self) -> Option<Turn> {
+        self.turns.pop()
+    }
+
+    pub fn last(&self) -> Option<&Turn> {
+        self.turns.last()
+    }
+
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    /// The text sent to the completions end point: the conversation so
+    /// far, in the same "Q:"/"A:" form as `reply.txt`, followed by the
+    /// new `prompt`
+    pub fn render_prompt(&self, prompt: &str) -> String {
+        let mut result = String::new();
+        for turn in self.turns.iter() {
+            result.push_str(&format!("Q: {}\nA: {}\n", turn.prompt, turn.answer));
+        }
+        result.push_str(&format!("Q: {prompt}\nA:"));
+        result
+    }
+
+    /// As `render_prompt` but with the start of the answer, `partial`,
+    /// so the model carries on from where it stopped
+    pub fn render_continuation(&self, prompt: &str, partial: &str) -> String {
+        format!("{} {partial}", self.render_prompt(prompt))
+    }
+
+    /// The messages sent to the chat end point: the conversation so
+    /// far, followed by the new `prompt` and its `images`.  If there is
+    /// a `partial` answer it follows, with a request to continue it
+    pub fn messages(
+        &self,
+        prompt: &str,
+        images: &[ImageUrl],
+        partial: Option<&str>,
+    ) -> Vec<ChatMessage> {
+        let mut result = Vec::new();
+        for turn in self.turns.iter() {
+            result.push(ChatMessage::user_with_images(&turn.prompt, &turn.images));
+            result.extend(turn.tool_messages.iter().cloned());
+            result.push(ChatMessage::assistant(&turn.answer));
+        }
+        result.push(ChatMessage::user_with_images(prompt, images));
+        if let Some(partial) = partial {
+            result.push(ChatMessage::assistant(partial));
+            result.push(ChatMessage::user(CONTINUE));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn render_and_undo() {
+        let mut conversation = Conversation::new();
+        assert_eq!(conversation.render_prompt("Hi"), "Q: Hi\nA:");
+        conversation.push("Hi".to_string(), "Hello".to_string());
+        conversation.push("Two".to_string(), "2".to_string());
+        assert_eq!(
+            conversation.render_prompt("Three"),
+            "Q: Hi\nA: Hello\nQ: Two\nA: 2\nQ: Three\nA:"
+        );
+        let turn = conversation.pop().unwrap();
+        assert_eq!(turn.prompt, "Two");
+        assert_eq!(
+            conversation.render_prompt("Two"),
+            "Q: Hi\nA: Hello\nQ: Two\nA:"
+        );
+        let messages = conversation.messages("Two", &[], Some("Tw"));
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant", "user"]);
+        assert_eq!(messages[3].text(), "Tw");
+    }
+}
@@ -0,0 +1,103 @@
+//! `doctor`: display the network settings and the credentials in
+//! effect, and check that the API can be reached with them
+use crate::api_client::ApiClient;
+use crate::network::Network;
+use crate::profiles::Credentials;
+use reqwest::Url;
+use serde_json::Value;
+use std::error::Error;
+use std::time::Instant;
+
+/// `err` and what caused it
+fn causes(err: &dyn Error) -> String {
+    let mut result = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        let text = err.to_string();
+        // Errors often repeat their source's message
+        if !result.contains(&text) {
+            result.push_str(&format!(": {text}"));
+        }
+        source = err.source();
+    }
+    result
+}
+
+fn check(passed: bool, message: &str) {
+    println!("{} {message}", if passed { "ok  " } else { "FAIL" });
+}
+
+/// For `doctor`.  The API is at `base_url`, and `credentials` are those
+/// the programme would use
+pub fn run_doctor(
+    network: &Network,
+    base_url: &str,
+    credentials: Result<Credentials, String>,
+) -> Result<(), String> {
+    let url = Url::parse(base_url).map_err(|err| format!("{base_url}: {err}"))?;
+    let host = url.host_str().unwrap_or_default();
+    println!("{:<12} {base_url}", "API");
+    for (name, value) in network.describe() {
+        println!("{name:<12} {value}");
+    }
+    let route = match network.proxy.as_ref() {
+        Some(_) if network.proxies(&url) => "through the proxy",
+        Some(_) if network.bypasses(host) => "directly, as it is in no proxy",
+        _ => "directly",
+    };
+    println!("{:<12} {host} is reached {route}", "Route");
+    match credentials.as_ref() {
+        Ok(credentials) => println!("{:<12} {}", "Key", credentials.describe()),
+        Err(err) => println!("{:<12} none: {err}", "Key"),
+    }
+    println!();
+
+    let client = match network.client() {
+        Ok(client) => client,
+        Err(err) => {
+            check(false, &err);
+            return Err("The HTTP client could not be made".to_string());
+        }
+    };
+    check(true, "The certificates and proxy settings are usable");
+
+    // Any answer, even one refusing an unauthenticated request, shows
+    // the proxy and TLS work
+    let started = Instant::now();
+    match client.get(format!("{base_url}/models")).send() {
+        Ok(response) => check(
+            true,
+            &format!(
+                "Reached {host} in {:.1}s (HTTP {})",
+                started.elapsed().as_secs_f64(),
+                response.status().as_u16()
+            ),
+        ),
+        Err(err) => {
+            check(false, &format!("Could not reach {host}: {}", causes(&err)));
+            return Err("The API cannot be reached".to_string());
+        }
+    }
+
+    let Ok(credentials) = credentials else {
+        check(false, "There is no API key");
+        return Err("There is no API key".to_string());
+    };
+    let api = ApiClient::new(client, credentials.key.clone())
+        .with_credentials(credentials)
+        .with_base_url(base_url);
+    match api.get_json::<Value>("/models") {
+        Ok(models) => {
+            let count = models["data"].as_array().map(Vec::len).unwrap_or(0);
+            check(
+                true,
+                &format!("The key works: {count} models are available"),
+            );
+            Ok(())
+        }
+        Err(err) => {
+            check(false, &format!("The key was refused: {err}"));
+            Err("The API key does not work".to_string())
+        }
+    }
+}
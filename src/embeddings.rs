@@ -0,0 +1,354 @@
+//! The embeddings end point, and a local index of embedded text.  The
+//! index is a flat list of chunks, each with its vector, saved as JSON.
+//! Searching compares the query with every chunk, which is fast enough
+//! for a few tens of thousands of chunks.  See
+//! https://platform.openai.com/docs/api-reference/embeddings
+use crate::api_client::{ApiClient, ApiError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MODEL: &str = "text-embedding-3-small";
+
+/// Inputs sent in each request
+const BATCH: usize = 100;
+
+/// Chunks are cut short at this many bytes, to stay inside the model's
+/// token limit
+const MAX_CHUNK_BYTES: usize = 6_000;
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Embed each of `inputs`, returning the vectors in the same order
+pub fn embed(api: &ApiClient, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, ApiError> {
+    let mut result = Vec::with_capacity(inputs.len());
+    for batch in inputs.chunks(BATCH) {
+        let mut response: EmbeddingResponse = api.post_json(
+            "/embeddings",
+            &EmbeddingRequest {
+                model,
+                input: batch,
+            },
+        )?;
+        if response.data.len() != batch.len() {
+            return Err(ApiError::Json(format!(
+                "{} embeddings for {} inputs",
+                response.data.len(),
+                batch.len()
+            )));
+        }
+        response.data.sort_by_key(|d| d.index);
+        result.extend(response.data.into_iter().map(|d| d.embedding));
+    }
+    Ok(result)
+}
+
+/// A piece of a file (or text given on the command line) and its vector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    /// The file, or "-" for text
+    pub source: String,
+    /// First and last line of `source`, from 1
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub vector: Vec<f32>,
+    /// Of the whole of `source` when it was embedded.  See `sha256`
+    #[serde(default)]
+    pub hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VectorIndex {
+    /// The embedding model.  Queries must use the same one
+    pub model: String,
+    pub chunks: Vec<Chunk>,
+}
+
+impl VectorIndex {
+    /// Load the index at `path`.  A missing file is an empty index
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                serde_json::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!("{}: {err}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = serde_json::to_string(self).map_err(|err| err.to_string())?;
+        fs::write(path, text).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    /// Replace any chunks from `source` with `chunks`
+    pub fn replace(&mut self, source: &str, chunks: Vec<Chunk>) {
+        self.chunks.retain(|c| c.source != source);
+        self.chunks.extend(chunks);
+    }
+
+    /// Add those of `chunks` whose text is not already in the index
+    /// from the same source.  Returns how many were added
+    pub fn add(&mut self, chunks: Vec<Chunk>) -> usize {
+        let before = self.chunks.len();
+        for chunk in chunks {
+            if !self
+                .chunks
+                .iter()
+                .any(|c| c.source == chunk.source && c.text == chunk.text)
+            {
+                self.chunks.push(chunk);
+            }
+        }
+        self.chunks.len() - before
+    }
+
+    /// The `k` chunks most similar to `query`, most similar first, with
+    /// their cosine similarity
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(f32, &Chunk)> {
+        let mut scored: Vec<(f32, &Chunk)> = self
+            .chunks
+            .iter()
+            .map(|c| (cosine(query, &c.vector), c))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(k);
+        scored
+    }
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        dot / denominator
+    }
+}
+
+/// Split `text` into pieces of `lines` lines (or fewer, if that is
+/// more than `MAX_CHUNK_BYTES`).  Returns the first and last line, from
+/// 1, and the text of each.  Blank pieces, and repeats of earlier
+/// pieces, are dropped
+pub fn chunk_text(text: &str, lines: usize) -> Vec<(usize, usize, String)> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut current = String::new();
+    let mut count = 0;
+    for (i, line) in text.lines().enumerate() {
+        if count == lines.max(1) || (count > 0 && current.len() + line.len() > MAX_CHUNK_BYTES) {
+            if !current.trim().is_empty() {
+                result.push((start + 1, i, std::mem::take(&mut current)));
+            }
+            current.clear();
+            count = 0;
+        }
+        if count == 0 {
+            start = i;
+        }
+        current.push_str(line);
+        current.push('\n');
+        count += 1;
+    }
+    if !current.trim().is_empty() {
+        result.push((start + 1, start + count, current));
+    }
+    let mut seen = HashSet::new();
+    result.retain(|(_, _, text)| seen.insert(text.clone()));
+    result
+}
+
+/// The text files under `path`.  Hidden files and directories are skipped
+pub fn text_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    let mut result = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+        Err(_) => return result,
+    };
+    entries.sort();
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .map(|n| n.to_string_lossy().starts_with('.'))
+            .unwrap_or(false);
+        if hidden {
+            continue;
+        }
+        if entry.is_dir() {
+            result.extend(text_files(&entry));
+        } else if fs::read_to_string(&entry).is_ok() {
+            result.push(entry);
+        }
+    }
+    result
+}
+
+/// Chunk and embed `text` from `source`
+pub fn embed_text(
+    api: &ApiClient,
+    model: &str,
+    source: &str,
+    text: &str,
+    lines: usize,
+) -> Result<Vec<Chunk>, ApiError> {
+    let pieces = chunk_text(text, lines);
+    let inputs: Vec<String> = pieces.iter().map(|(_, _, t)| t.clone()).collect();
+    let vectors = embed(api, model, &inputs)?;
+    let hash = sha256(text.as_bytes());
+    Ok(pieces
+        .into_iter()
+        .zip(vectors)
+        .map(|((start_line, end_line, text), vector)| Chunk {
+            source: source.to_string(),
+            start_line,
+            end_line,
+            text,
+            vector,
+            hash: hash.clone(),
+        })
+        .collect())
+}
+
+/// Hex encoded SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The `embed` subcommand: embed `paths` (files or directories) and
+/// `text`, and add them to the index at `index_path`
+pub fn run_embed(
+    api: &ApiClient,
+    paths: &[PathBuf],
+    text: Option<&str>,
+    index_path: &Path,
+    model: Option<&str>,
+    lines: usize,
+) -> Result<(), String> {
+    let mut index = VectorIndex::load(index_path)?;
+    let model = match (model, index.model.as_str()) {
+        (Some(model), "") => model.to_string(),
+        (Some(model), existing) if model != existing => {
+            return Err(format!(
+                "{} was made with {existing}, not {model}",
+                index_path.display()
+            ))
+        }
+        (_, "") => DEFAULT_MODEL.to_string(),
+        (_, existing) => existing.to_string(),
+    };
+    index.model = model.clone();
+    if let Some(text) = text {
+        let chunks = embed_text(api, &model, "-", text, lines).map_err(|e| e.to_string())?;
+        let count = chunks.len();
+        let added = index.add(chunks);
+        println!("-: {count} chunks, {added} new");
+    }
+    for path in paths.iter().flat_map(|p| text_files(p)) {
+        let source = path.display().to_string();
+        let content = fs::read_to_string(&path).map_err(|err| format!("{source}: {err}"))?;
+        let chunks =
+            embed_text(api, &model, &source, &content, lines).map_err(|e| e.to_string())?;
+        println!("{source}: {} chunks", chunks.len());
+        index.replace(&source, chunks);
+    }
+    index.save(index_path)
+}
+
+/// The `search` subcommand: display the `k` chunks nearest `query`
+pub fn run_search(api: &ApiClient, query: &str, index_path: &Path, k: usize) -> Result<(), String> {
+    let index = VectorIndex::load(index_path)?;
+    if index.chunks.is_empty() {
+        return Err(format!(
+            "{} is empty.  Use `embed` first",
+            index_path.display()
+        ));
+    }
+    let vector = embed(api, &index.model, &[query.to_string()])
+        .map_err(|e| e.to_string())?
+        .remove(0);
+    for (score, chunk) in index.search(&vector, k) {
+        println!(
+            "\x1b[1m{score:.4} {}:{}-{}\x1b[0m",
+            chunk.source, chunk.start_line, chunk.end_line
+        );
+        for line in chunk.text.lines().take(5) {
+            println!("    {line}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn chunks_and_search() {
+        let text = "a\nb\n\n\nc\nd\ne\n";
+        assert_eq!(
+            chunk_text(text, 2),
+            vec![
+                (1, 2, "a\nb\n".to_string()),
+                (5, 6, "c\nd\n".to_string()),
+                (7, 7, "e\n".to_string())
+            ]
+        );
+        assert_eq!(
+            chunk_text("a\nb\na\nb\nc\n", 2),
+            vec![(1, 2, "a\nb\n".to_string()), (5, 5, "c\n".to_string())]
+        );
+        let chunk = |source: &str, vector: Vec<f32>| Chunk {
+            source: source.to_string(),
+            start_line: 1,
+            end_line: 1,
+            text: String::new(),
+            vector,
+            hash: String::new(),
+        };
+        let mut index = VectorIndex::default();
+        index.replace(
+            "x",
+            vec![chunk("x", vec![1.0, 0.0]), chunk("x", vec![0.0, 1.0])],
+        );
+        index.replace("y", vec![chunk("y", vec![1.0, 1.0])]);
+        let found = index.search(&[1.0, 0.1], 2);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].1.vector, vec![1.0, 0.0]);
+        assert_eq!(found[1].1.source, "y");
+        index.replace("x", Vec::new());
+        assert_eq!(index.chunks.len(), 1);
+        let text = |text: &str| Chunk {
+            text: text.to_string(),
+            ..chunk("-", vec![1.0])
+        };
+        assert_eq!(index.add(vec![text("one"), text("two")]), 2);
+        assert_eq!(index.add(vec![text("two"), text("three")]), 1);
+        assert_eq!(index.chunks.len(), 4);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -0,0 +1,479 @@
+//! Comparing models, or parameters, on a dataset of prompts with the
+//! answers expected.  The dataset is JSONL, a case on each line:
+//!
+//! ```text
+//! {"id": "capital", "input": "What is the capital of France?", "expected": "Paris", "scorer": "contains"}
+//! {"input": "Give the date as JSON", "expected": {"date.year": 2024}, "scorer": "json"}
+//! ```
+//!
+//! `scorer` is optional, the default is set on the command line.  Each
+//! variant, a model with parameters, answers each case, the answers are
+//! scored from 0 to 1, and the results are displayed as a table and
+//! saved as a JSON report
+use crate::backend::Backend;
+use crate::chat::{self, ChatMessage, ChatRequest};
+use crate::completions::CompletionRequestInfo;
+use crate::conversation::Conversation;
+use crate::sampling::Sampling;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The judge's instructions.  `{input}`, `{expected}` and `{answer}`
+/// are replaced
+const JUDGE_PROMPT: &str = "You are grading an answer to a question.
+
+Question:
+{input}
+
+Reference answer:
+{expected}
+
+Answer to grade:
+{answer}
+
+How well does the answer agree with the reference answer?  Reply with only a score from 0 to 10.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scorer {
+    /// The answer, trimmed, is the expected text
+    Exact,
+    /// The answer contains the expected text
+    Contains,
+    /// The answer matches the expected regular expression
+    Regex,
+    /// The answer is JSON, and the expected object's fields, paths like
+    /// `a.b.0`, have those values.  Scored by the fraction that do
+    Json,
+    /// Another model grades the answer against the expected one
+    Judge,
+}
+
+impl Scorer {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        serde_json::from_value(Value::String(name.to_string()))
+            .map_err(|_| format!("{name}: the scorer is exact, contains, regex, json or judge"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Case {
+    #[serde(default)]
+    pub id: String,
+    pub input: String,
+    #[serde(default)]
+    pub expected: Value,
+    pub scorer: Option<Scorer>,
+}
+
+/// Read the dataset.  Cases without an `id` are named by line number
+pub fn load(path: &Path) -> Result<Vec<Case>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut cases = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut case: Case = serde_json::from_str(line)
+            .map_err(|err| format!("{} line {}: {err}", path.display(), i + 1))?;
+        if case.id.is_empty() {
+            case.id = format!("line {}", i + 1);
+        }
+        cases.push(case);
+    }
+    if cases.is_empty() {
+        return Err(format!("{}: there are no cases", path.display()));
+    }
+    Ok(cases)
+}
+
+/// A model and its parameters
+#[derive(Debug, Clone)]
+pub struct Variant {
+    /// As it was given
+    pub name: String,
+    pub request_info: CompletionRequestInfo,
+}
+
+impl Variant {
+    /// `spec` is a model then parameters, "gpt-4o temperature=0 top_p=0.5".
+    /// Parameters not given are those of `base`
+    pub fn parse(spec: &str, base: &CompletionRequestInfo) -> Result<Self, String> {
+        let mut words = spec.split_whitespace();
+        let mut request_info = base.clone();
+        request_info.model = words
+            .next()
+            .ok_or_else(|| "A variant needs a model".to_string())?
+            .to_string();
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("{word}: parameters are key=value"))?;
+            let number_error = || format!("{key}: {value} is not a number");
+            match key {
+                "temperature" => {
+                    request_info.temperature = value.parse().map_err(|_| number_error())?
+                }
+                "max_tokens" => {
+                    request_info.max_tokens = value.parse().map_err(|_| number_error())?
+                }
+                _ => request_info.sampling.set(key, value)?,
+            }
+        }
+        Ok(Self {
+            name: spec.split_whitespace().collect::<Vec<_>>().join(" "),
+            request_info,
+        })
+    }
+}
+
+pub struct JudgeOptions {
+    pub model: String,
+    /// Instead of `JUDGE_PROMPT`
+    pub prompt: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaseResult {
+    pub id: String,
+    pub answer: Option<String>,
+    pub score: f64,
+    pub latency_ms: u128,
+    /// Asking for the answer failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ask_error: Option<String>,
+    /// Scoring the answer failed: the judge gave no score, or
+    /// `expected` does not suit the scorer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantReport {
+    pub variant: String,
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub sampling: Sampling,
+    pub mean_score: f64,
+    pub passed: usize,
+    pub errors: usize,
+    pub mean_latency_ms: u128,
+    pub results: Vec<CaseResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub dataset: String,
+    /// The score a case needs to pass
+    pub pass_score: f64,
+    pub variants: Vec<VariantReport>,
+}
+
+/// Score `answer` to `case`.  Errors are in the case, or the judge
+pub fn score(
+    backend: &Backend,
+    case: &Case,
+    scorer: Scorer,
+    answer: &str,
+    judge: &JudgeOptions,
+) -> Result<f64, String> {
+    let expected_text = match &case.expected {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    };
+    let binary = |pass: bool| if pass { 1.0 } else { 0.0 };
+    match scorer {
+        Scorer::Exact => Ok(binary(answer.trim() == expected_text.trim())),
+        Scorer::Contains => Ok(binary(answer.contains(expected_text.as_str()))),
+        Scorer::Regex => {
+            let regex =
+                Regex::new(&expected_text).map_err(|err| format!("{expected_text}: {err}"))?;
+            Ok(binary(regex.is_match(answer)))
+        }
+        Scorer::Json => score_json(&case.expected, answer),
+        Scorer::Judge => {
+            let prompt = judge
+                .prompt
+                .as_deref()
+                .unwrap_or(JUDGE_PROMPT)
+                .replace("{input}", &case.input)
+                .replace("{expected}", &expected_text)
+                .replace("{answer}", answer);
+            let messages = [ChatMessage::user(&prompt)];
+            let request = ChatRequest {
+                model: &judge.model,
+                messages: &messages,
+                temperature: 0.0,
+                max_tokens: 100,
+                n: 1,
+                logprobs: None,
+                top_logprobs: None,
+                tools: &[],
+                tool_choice: None,
+                sampling: &Sampling::default(),
+                fresh: false,
+            };
+            let response = chat::complete(&backend.api, &request).map_err(|e| e.to_string())?;
+            let verdict = response
+                .choices
+                .first()
+                .map(|c| c.message.text())
+                .unwrap_or_default();
+            let score = judge_score(&verdict)
+                .ok_or_else(|| format!("The judge did not give a score: {verdict}"))?;
+            backend.api.keep(&response.cache);
+            Ok(score)
+        }
+    }
+}
+
+/// The fraction of the fields in `expected` that the JSON in `answer`
+/// has.  The JSON may be in a code block, or have text around it
+fn score_json(expected: &Value, answer: &str) -> Result<f64, String> {
+    let fields = expected
+        .as_object()
+        .filter(|fields| !fields.is_empty())
+        .ok_or("`expected` must be an object of fields for the json scorer")?;
+    let json = match (answer.find(['{', '[']), answer.rfind(['}', ']'])) {
+        (Some(start), Some(end)) if start < end => &answer[start..=end],
+        _ => return Ok(0.0),
+    };
+    let value: Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return Ok(0.0),
+    };
+    let matching = fields
+        .iter()
+        .filter(|(path, expected)| {
+            let pointer = if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{}", path.replace('.', "/"))
+            };
+            value.pointer(&pointer) == Some(expected)
+        })
+        .count();
+    Ok(matching as f64 / fields.len() as f64)
+}
+
+/// The first number in the judge's reply, out of 10
+fn judge_score(verdict: &str) -> Option<f64> {
+    let start = verdict.find(|c: char| c.is_ascii_digit())?;
+    let number: String = verdict[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let score: f64 = number.trim_end_matches('.').parse().ok()?;
+    Some((score / 10.0).clamp(0.0, 1.0))
+}
+
+/// Ask `variant` each case, and score the answers
+pub fn run_variant(
+    backend: &Backend,
+    variant: &Variant,
+    cases: &[Case],
+    default_scorer: Scorer,
+    judge: &JudgeOptions,
+    pass_score: f64,
+) -> VariantReport {
+    let mut results = Vec::new();
+    for case in cases {
+        let started = Instant::now();
+        let answer = backend
+            .ask(
+                &variant.request_info,
+                &Conversation::new(),
+                &case.input,
+                &[],
+                None,
+                &mut |_| false,
+            )
+            .and_then(|answers| {
+                backend.api.keep(&answers.cache);
+                answers
+                    .choices
+                    .into_iter()
+                    .next()
+                    .map(|c| c.text.trim().to_string())
+                    .ok_or_else(|| "No answer".to_string())
+            });
+        let latency_ms = started.elapsed().as_millis();
+        let (answer, ask_error) = match answer {
+            Ok(answer) => (Some(answer), None),
+            Err(err) => (None, Some(err)),
+        };
+        let scored = answer.as_deref().map(|answer| {
+            score(
+                backend,
+                case,
+                case.scorer.unwrap_or(default_scorer),
+                answer,
+                judge,
+            )
+        });
+        let result = CaseResult {
+            id: case.id.clone(),
+            answer,
+            score: match scored {
+                Some(Ok(score)) => score,
+                _ => 0.0,
+            },
+            latency_ms,
+            ask_error,
+            score_error: scored.and_then(Result::err),
+        };
+        match (result.ask_error.as_deref(), result.score_error.as_deref()) {
+            (Some(err), _) => println!(
+                "{} {}: \x1b[1;31masking failed: {err}\x1b[0m",
+                variant.name, case.id
+            ),
+            (None, Some(err)) => println!(
+                "{} {}: \x1b[1;31mscoring failed: {err}\x1b[0m",
+                variant.name, case.id
+            ),
+            (None, None) => println!("{} {}: {:.2}", variant.name, case.id, result.score),
+        }
+        results.push(result);
+    }
+    let count = results.len().max(1);
+    VariantReport {
+        variant: variant.name.clone(),
+        model: variant.request_info.model.clone(),
+        temperature: variant.request_info.temperature,
+        max_tokens: variant.request_info.max_tokens,
+        sampling: variant.request_info.sampling.clone(),
+        mean_score: results.iter().map(|r| r.score).sum::<f64>() / count as f64,
+        passed: results.iter().filter(|r| r.score >= pass_score).count(),
+        errors: results
+            .iter()
+            .filter(|r| r.ask_error.is_some() || r.score_error.is_some())
+            .count(),
+        mean_latency_ms: results.iter().map(|r| r.latency_ms).sum::<u128>() / count as u128,
+        results,
+    }
+}
+
+/// The variants side by side, best first
+pub fn table(report: &Report) -> String {
+    let mut variants: Vec<&VariantReport> = report.variants.iter().collect();
+    variants.sort_by(|a, b| b.mean_score.total_cmp(&a.mean_score));
+    let width = variants
+        .iter()
+        .map(|v| v.variant.len())
+        .max()
+        .unwrap_or(0)
+        .max("VARIANT".len());
+    let mut result = format!(
+        "{:<width$} {:>6} {:>8} {:>7} {:>10}\n",
+        "VARIANT", "SCORE", "PASSED", "ERRORS", "LATENCY"
+    );
+    for v in variants {
+        result.push_str(&format!(
+            "{:<width$} {:>6.2} {:>8} {:>7} {:>7} ms\n",
+            v.variant,
+            v.mean_score,
+            format!("{}/{}", v.passed, v.results.len()),
+            v.errors,
+            v.mean_latency_ms
+        ));
+    }
+    result
+}
+
+/// The options of the `eval` subcommand
+pub struct EvalOptions {
+    /// Models with parameters.  See `Variant::parse`
+    pub variants: Vec<String>,
+    /// For cases that do not have a `scorer`
+    pub scorer: String,
+    pub judge_model: String,
+    /// A file with the judge's instructions
+    pub judge_prompt: Option<PathBuf>,
+    pub pass_score: f64,
+    pub report: PathBuf,
+}
+
+/// For `eval`.  Runs each variant on the dataset, displays the table
+/// and writes the report.  With no variants the model and parameters
+/// in `base` are evaluated
+pub fn run_eval(
+    backend: &Backend,
+    dataset: &Path,
+    base: &CompletionRequestInfo,
+    options: &EvalOptions,
+) -> Result<(), String> {
+    let cases = load(dataset)?;
+    let scorer = Scorer::parse(&options.scorer)?;
+    let variants = if options.variants.is_empty() {
+        vec![Variant {
+            name: base.model.clone(),
+            request_info: base.clone(),
+        }]
+    } else {
+        options
+            .variants
+            .iter()
+            .map(|spec| Variant::parse(spec, base))
+            .collect::<Result<Vec<Variant>, String>>()?
+    };
+    let judge = JudgeOptions {
+        model: options.judge_model.clone(),
+        prompt: match options.judge_prompt.as_deref() {
+            Some(path) => {
+                Some(fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?)
+            }
+            None => None,
+        },
+    };
+    let report = Report {
+        dataset: dataset.display().to_string(),
+        pass_score: options.pass_score,
+        variants: variants
+            .iter()
+            .map(|v| run_variant(backend, v, &cases, scorer, &judge, options.pass_score))
+            .collect(),
+    };
+    print!("{}", table(&report));
+    fs::write(
+        &options.report,
+        serde_json::to_string_pretty(&report).unwrap() + "\n",
+    )
+    .map_err(|err| format!("{}: {err}", options.report.display()))?;
+    println!("Report saved in {}", options.report.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn scoring() {
+        assert_eq!(Scorer::parse("json"), Ok(Scorer::Json));
+        assert!(Scorer::parse("fuzzy").is_err());
+
+        let expected = json!({"date.year": 2024, "date.month": 5, "/tags/0": "a"});
+        let answer = "Here it is:\n```json\n{\"date\": {\"year\": 2024, \"month\": 6}, \"tags\": [\"a\"]}\n```";
+        let score = score_json(&expected, answer).unwrap();
+        assert!((score - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(score_json(&expected, "no JSON").unwrap(), 0.0);
+        assert!(score_json(&json!("text"), "{}").is_err());
+
+        assert_eq!(judge_score("8"), Some(0.8));
+        assert_eq!(judge_score("Score: 10."), Some(1.0));
+        assert_eq!(judge_score("None"), None);
+
+        let base = CompletionRequestInfo::new(String::new(), "gpt-4o".to_string(), 0.9, 100);
+        let variant = Variant::parse("gpt-4o-mini  temperature=0 seed=1", &base).unwrap();
+        assert_eq!(variant.name, "gpt-4o-mini temperature=0 seed=1");
+        assert_eq!(variant.request_info.temperature, 0.0);
+        assert_eq!(variant.request_info.sampling.seed, Some(1));
+        assert!(Variant::parse("gpt-4o top_p", &base).is_err());
+    }
+}
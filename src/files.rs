@@ -0,0 +1,244 @@
+//! Files uploaded to OpenAI, for fine-tuning, batches and so on.  See
+//! https://platform.openai.com/docs/api-reference/files
+use crate::api_client::ApiClient;
+use reqwest::blocking::multipart::{Form, Part};
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileObject {
+    pub id: String,
+    pub filename: String,
+    pub bytes: u64,
+    pub purpose: String,
+    /// Unix time
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileList {
+    data: Vec<FileObject>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Deleted {
+    deleted: bool,
+}
+
+/// Displays how much of a file has been sent, as it is uploaded
+struct Progress {
+    file: Cursor<Vec<u8>>,
+    name: String,
+    sent: u64,
+    total: u64,
+}
+
+impl Read for Progress {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.sent += n as u64;
+        eprint!(
+            "\rUploading {}: {}% ({}/{} bytes)",
+            self.name,
+            (self.sent * 100).checked_div(self.total).unwrap_or(100),
+            self.sent,
+            self.total
+        );
+        if self.sent == self.total {
+            eprintln!();
+        }
+        Ok(n)
+    }
+}
+
+pub fn list(api: &ApiClient, purpose: Option<&str>) -> Result<Vec<FileObject>, String> {
+    let path = match purpose {
+        Some(purpose) => format!("/files?purpose={purpose}"),
+        None => "/files".to_string(),
+    };
+    let list: FileList = api.get_json(&path).map_err(|e| e.to_string())?;
+    Ok(list.data)
+}
+
+pub fn retrieve(api: &ApiClient, id: &str) -> Result<FileObject, String> {
+    api.get_json(&format!("/files/{id}"))
+        .map_err(|e| e.to_string())
+}
+
+/// Upload `path` for `purpose`: "fine-tune", "batch", "assistants",
+/// "vision"...  Progress is displayed on stderr
+pub fn upload(api: &ApiClient, path: &Path, purpose: &str) -> Result<FileObject, String> {
+    // Read once, so every attempt sends the same contents
+    let contents = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let total = contents.len() as u64;
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "file".to_string());
+    let form = || {
+        let part = Part::reader_with_length(
+            Progress {
+                file: Cursor::new(contents.clone()),
+                name: name.clone(),
+                sent: 0,
+                total,
+            },
+            total,
+        );
+        Form::new()
+            .text("purpose", purpose.to_string())
+            .part("file", part.file_name(name.clone()))
+    };
+    let body = api
+        .post_multipart("/files", &form)
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&body).map_err(|err| format!("Bad response: {err}"))
+}
+
+/// The contents of a file
+pub fn download(api: &ApiClient, id: &str) -> Result<Vec<u8>, String> {
+    api.get_bytes(&format!("/files/{id}/content"))
+        .map_err(|e| e.to_string())
+}
+
+pub fn delete(api: &ApiClient, id: &str) -> Result<(), String> {
+    let deleted: Deleted = api
+        .delete_json(&format!("/files/{id}"))
+        .map_err(|e| e.to_string())?;
+    if deleted.deleted {
+        Ok(())
+    } else {
+        Err(format!("{id} was not deleted"))
+    }
+}
+
+/// "YYYY-MM-DD HH:MM" in UTC for a Unix time
+pub fn format_time(time: u64) -> String {
+    let days = (time / 86400) as i64;
+    let minutes = time % 86400 / 60;
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// The files as a table, newest first
+pub fn table(files: &[FileObject]) -> String {
+    let mut files: Vec<&FileObject> = files.iter().collect();
+    files.sort_by_key(|f| Reverse(f.created_at));
+    let mut result = format!(
+        "{:<30} {:<32} {:>12} {:<12} {}\n",
+        "ID", "FILENAME", "BYTES", "PURPOSE", "CREATED"
+    );
+    for file in files {
+        result.push_str(&format!(
+            "{:<30} {:<32} {:>12} {:<12} {}\n",
+            file.id,
+            file.filename,
+            file.bytes,
+            file.purpose,
+            format_time(file.created_at)
+        ));
+    }
+    result
+}
+
+/// For `files list`
+pub fn run_list(api: &ApiClient, purpose: Option<&str>) -> Result<(), String> {
+    print!("{}", table(&list(api, purpose)?));
+    Ok(())
+}
+
+/// For `files upload`
+pub fn run_upload(api: &ApiClient, path: &Path, purpose: &str) -> Result<(), String> {
+    let file = upload(api, path, purpose)?;
+    print!("{}", table(&[file]));
+    Ok(())
+}
+
+/// For `files download`.  Without `output` the file is saved under
+/// its own name, which must not exist already
+pub fn run_download(api: &ApiClient, id: &str, output: Option<&Path>) -> Result<(), String> {
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => {
+            let file = retrieve(api, id)?;
+            let path = PathBuf::from(Path::new(&file.filename).file_name().unwrap_or(id.as_ref()));
+            if path.exists() {
+                return Err(format!(
+                    "{} exists.  Use --output to choose another file",
+                    path.display()
+                ));
+            }
+            path
+        }
+    };
+    let bytes = download(api, id)?;
+    fs::write(&output, &bytes).map_err(|err| format!("{}: {err}", output.display()))?;
+    println!("Saved {} ({} bytes)", output.display(), bytes.len());
+    Ok(())
+}
+
+/// For `files delete`
+pub fn run_delete(api: &ApiClient, id: &str) -> Result<(), String> {
+    delete(api, id)?;
+    println!("Deleted {id}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_key::ApiKey;
+    use crate::mock_server::MockServer;
+    use reqwest::blocking::Client;
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00");
+    }
+
+    #[test]
+    fn upload_sends_the_file_each_time() {
+        let dir = std::env::temp_dir().join(format!("files-upload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("train.jsonl");
+        fs::write(&path, "{\"prompt\": \"Hi\"}\n").unwrap();
+        let fixtures = serde_json::from_str(
+            r#"[{"path": "/v1/files", "status": 500, "times": 1,
+                 "body": {"error": {"message": "Try again"}}},
+                {"path": "/v1/files", "body": {"id": "file-1", "filename": "train.jsonl",
+                 "bytes": 16, "purpose": "fine-tune", "created_at": 0}}]"#,
+        )
+        .unwrap();
+        let server = MockServer::start(0, fixtures).unwrap();
+        let api = ApiClient::new(Client::new(), ApiKey::new("sk-test")).with_base_url(&server.url);
+        assert_eq!(upload(&api, &path, "fine-tune").unwrap().id, "file-1");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|r| r.body.contains("{\"prompt\": \"Hi\"}")));
+
+        let missing = dir.join("missing.jsonl");
+        assert!(upload(&api, &missing, "fine-tune").is_err());
+        assert_eq!(server.requests().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+//! Fine-tuning jobs: checking training data, starting a job, following
+//! it, and cancelling it.  See
+//! https://platform.openai.com/docs/api-reference/fine-tuning
+use crate::api_client::ApiClient;
+use crate::files;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// The API will not start a job with fewer examples
+const MIN_EXAMPLES: usize = 10;
+
+/// Examples longer than this (estimated) are truncated in training
+const MAX_EXAMPLE_TOKENS: usize = 65_536;
+
+/// Seconds between checks on a running job
+const POLL_SECONDS: u64 = 10;
+
+/// Jobs in these states will not change
+const FINISHED: [&str; 3] = ["succeeded", "failed", "cancelled"];
+
+/// Fine-tuned models are called "ft:..." or, for older ones,
+/// "babbage:ft-personal:..."
+pub fn is_fine_tuned(model: &str) -> bool {
+    model.starts_with("ft:") || model.contains(":ft-")
+}
+
+/// What `validate` found
+#[derive(Debug, Default)]
+pub struct Report {
+    pub examples: usize,
+    /// Estimated, at four characters a token
+    pub tokens: usize,
+    pub longest: usize,
+    /// Problems that will stop the job.  "line N: ..."
+    pub errors: Vec<String>,
+    /// Problems that may make it train badly
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn describe(&self) -> String {
+        let mut result = format!(
+            "{} examples, about {} tokens, the longest about {}\n",
+            self.examples, self.tokens, self.longest
+        );
+        for error in self.errors.iter() {
+            result.push_str(&format!("Error: {error}\n"));
+        }
+        for warning in self.warnings.iter() {
+            result.push_str(&format!("Warning: {warning}\n"));
+        }
+        result
+    }
+}
+
+/// Check that `data`, JSONL, is training data in the chat format
+/// (`{"messages": [...]}`) or the older prompt and completion format,
+/// with at least `min_examples` examples.  Token counts are estimates
+pub fn validate(data: &str, min_examples: usize) -> Report {
+    let mut report = Report::default();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = i + 1;
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(err) => {
+                report.errors.push(format!("line {line_number}: {err}"));
+                continue;
+            }
+        };
+        let text = match example_text(&value) {
+            Ok(text) => text,
+            Err(err) => {
+                report.errors.push(format!("line {line_number}: {err}"));
+                continue;
+            }
+        };
+        let tokens = text.chars().count().div_ceil(4);
+        if tokens > MAX_EXAMPLE_TOKENS {
+            report.warnings.push(format!(
+                "line {line_number}: about {tokens} tokens, more than {MAX_EXAMPLE_TOKENS}"
+            ));
+        }
+        report.examples += 1;
+        report.tokens += tokens;
+        report.longest = report.longest.max(tokens);
+    }
+    if report.examples < min_examples {
+        report.errors.push(format!(
+            "{} examples.  At least {min_examples} are needed",
+            report.examples
+        ));
+    }
+    report
+}
+
+/// The text of one example, if it is well formed
+fn example_text(value: &Value) -> Result<String, String> {
+    if let Some(messages) = value.get("messages") {
+        let messages = messages
+            .as_array()
+            .ok_or_else(|| "`messages` is not an array".to_string())?;
+        let mut text = String::new();
+        let mut assistant = false;
+        for message in messages {
+            let role = message["role"]
+                .as_str()
+                .ok_or_else(|| "a message has no role".to_string())?;
+            if !["system", "user", "assistant", "tool"].contains(&role) {
+                return Err(format!("unknown role `{role}`"));
+            }
+            assistant |= role == "assistant";
+            match &message["content"] {
+                Value::String(content) => text.push_str(content),
+                Value::Null if message.get("tool_calls").is_some() => (),
+                _ => return Err(format!("a `{role}` message has no content")),
+            }
+        }
+        if !assistant {
+            return Err("no assistant message to learn from".to_string());
+        }
+        Ok(text)
+    } else {
+        match (value["prompt"].as_str(), value["completion"].as_str()) {
+            (Some(prompt), Some(completion)) => Ok(format!("{prompt}{completion}")),
+            _ => Err("neither `messages` nor `prompt` and `completion`".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Hyperparameters {
+    n_epochs: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateJob<'a> {
+    model: &'a str,
+    training_file: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validation_file: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hyperparameters: Option<Hyperparameters>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub model: String,
+    pub status: String,
+    pub fine_tuned_model: Option<String>,
+    pub trained_tokens: Option<u64>,
+    pub error: Option<Value>,
+}
+
+impl Job {
+    pub fn describe(&self) -> String {
+        let mut result = format!("{} {} ({})", self.id, self.status, self.model);
+        if let Some(model) = self.fine_tuned_model.as_ref() {
+            result.push_str(&format!(" -> {model}"));
+        }
+        if let Some(tokens) = self.trained_tokens {
+            result.push_str(&format!(", {tokens} tokens trained"));
+        }
+        if let Some(message) = self.error.as_ref().and_then(|e| e["message"].as_str()) {
+            result.push_str(&format!("\n{message}"));
+        }
+        result
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    id: String,
+    created_at: u64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventList {
+    data: Vec<Event>,
+}
+
+/// What `create` needs
+#[derive(Debug)]
+pub struct JobOptions<'a> {
+    pub model: &'a str,
+    pub validation: Option<&'a str>,
+    pub suffix: Option<&'a str>,
+    pub epochs: Option<u32>,
+}
+
+/// A file id for `training`: a local file is checked and uploaded,
+/// anything else is taken to be the id of an uploaded file
+fn training_file(api: &ApiClient, training: &str, min_examples: usize) -> Result<String, String> {
+    let path = Path::new(training);
+    if !path.exists() {
+        return Ok(training.to_string());
+    }
+    let data = fs::read_to_string(path).map_err(|err| format!("{training}: {err}"))?;
+    let report = validate(&data, min_examples);
+    print!("{}", report.describe());
+    if !report.errors.is_empty() {
+        return Err(format!("{training} is not valid training data"));
+    }
+    Ok(files::upload(api, path, "fine-tune")?.id)
+}
+
+/// Start fine-tuning `options.model` on `training`, a file or file id
+pub fn create(api: &ApiClient, training: &str, options: &JobOptions) -> Result<Job, String> {
+    let training_file = training_file(api, training, MIN_EXAMPLES)?;
+    let validation_file = match options.validation {
+        Some(validation) => Some(self::training_file(api, validation, 1)?),
+        None => None,
+    };
+    let request = CreateJob {
+        model: options.model,
+        training_file: &training_file,
+        validation_file: validation_file.as_deref(),
+        suffix: options.suffix,
+        hyperparameters: options.epochs.map(|n_epochs| Hyperparameters { n_epochs }),
+    };
+    api.post_json("/fine_tuning/jobs", &request)
+        .map_err(|e| e.to_string())
+}
+
+pub fn retrieve(api: &ApiClient, id: &str) -> Result<Job, String> {
+    api.get_json(&format!("/fine_tuning/jobs/{id}"))
+        .map_err(|e| e.to_string())
+}
+
+pub fn cancel(api: &ApiClient, id: &str) -> Result<Job, String> {
+    api.post_json(
+        &format!("/fine_tuning/jobs/{id}/cancel"),
+        &serde_json::json!({}),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// For `finetune validate`
+pub fn run_validate(path: &Path) -> Result<(), String> {
+    let data = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let report = validate(&data, MIN_EXAMPLES);
+    print!("{}", report.describe());
+    if report.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} is not valid training data", path.display()))
+    }
+}
+
+/// For `finetune create`
+pub fn run_create(api: &ApiClient, training: &str, options: &JobOptions) -> Result<(), String> {
+    let job = create(api, training, options)?;
+    println!("{}", job.describe());
+    println!("`finetune status {}` to follow it", job.id);
+    Ok(())
+}
+
+/// For `finetune status`.  Displays the job's events as they happen
+/// until it finishes, unless `follow` is false
+pub fn run_status(api: &ApiClient, id: &str, follow: bool) -> Result<(), String> {
+    let mut seen: HashSet<String> = HashSet::new();
+    loop {
+        let events: EventList = api
+            .get_json(&format!("/fine_tuning/jobs/{id}/events?limit=100"))
+            .map_err(|e| e.to_string())?;
+        // Newest first
+        for event in events.data.iter().rev() {
+            if seen.insert(event.id.clone()) {
+                println!("{} {}", files::format_time(event.created_at), event.message);
+            }
+        }
+        let job = retrieve(api, id)?;
+        if !follow || FINISHED.contains(&job.status.as_str()) {
+            println!("{}", job.describe());
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(POLL_SECONDS));
+    }
+}
+
+/// For `finetune cancel`
+pub fn run_cancel(api: &ApiClient, id: &str) -> Result<(), String> {
+    println!("{}", cancel(api, id)?.describe());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelExampleData;
+    #[test]
+    fn training_data() {
+        let good = r#"{"messages": [{"role": "user", "content": "Hi"}, {"role": "assistant", "content": "Hello"}]}"#;
+        let data = [good; 10].join("\n");
+        let report = validate(&data, MIN_EXAMPLES);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.examples, 10);
+        assert_eq!(report.longest, 2);
+
+        let data = format!(
+            "{good}\nnot json\n{}\n{}",
+            r#"{"messages": [{"role": "user", "content": "Hi"}]}"#,
+            r#"{"prompt": "Q", "completion": "A"}"#
+        );
+        let report = validate(&data, MIN_EXAMPLES);
+        assert_eq!(report.examples, 2);
+        assert!(report.errors[0].starts_with("line 2:"));
+        assert!(report.errors[1].starts_with("line 3: no assistant"));
+        assert!(report.errors[2].contains("At least 10"));
+    }
+
+    #[test]
+    fn fine_tuned_models() {
+        let models: Value = serde_json::from_str(&ModelExampleData::new().json).unwrap();
+        let fine_tuned: Vec<&str> = models["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|m| m["id"].as_str())
+            .filter(|id| is_fine_tuned(id))
+            .collect();
+        assert_eq!(fine_tuned.len(), 2);
+        assert!(is_fine_tuned("ft:gpt-4o-mini-2024-07-18:org::abc123"));
+        assert!(!is_fine_tuned("gpt-4o-mini"));
+    }
+}
@@ -0,0 +1,178 @@
+//! The image generation end point.  Images are returned base64 encoded
+//! and saved as PNG files named after the prompt.  See
+//! https://platform.openai.com/docs/api-reference/images/create
+use crate::api_client::ApiClient;
+use crate::session_log::{SessionEvent, SessionLog};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_MODEL: &str = "dall-e-3";
+
+/// The longest part of a file name taken from the prompt
+const NAME_LENGTH: usize = 40;
+
+#[derive(Debug, Clone)]
+pub struct ImageOptions {
+    pub model: String,
+    /// "1024x1024", "1792x1024"...
+    pub size: String,
+    /// "standard", "hd", or for gpt-image-1 "low", "medium", "high"
+    pub quality: Option<String>,
+    pub n: u32,
+    /// "vivid" or "natural" (dall-e-3)
+    pub style: Option<String>,
+    /// Where to save the images
+    pub dir: PathBuf,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            model: DEFAULT_MODEL.to_string(),
+            size: "1024x1024".to_string(),
+            quality: None,
+            n: 1,
+            style: None,
+            dir: PathBuf::from("."),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImageRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    n: u32,
+    size: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    style: Option<&'a str>,
+    /// gpt-image-1 always returns base64 and does not accept this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    b64_json: Option<String>,
+    /// dall-e-3 rewrites prompts.  This is what it used
+    revised_prompt: Option<String>,
+}
+
+/// An image that has been saved
+#[derive(Debug)]
+pub struct SavedImage {
+    pub path: PathBuf,
+    pub revised_prompt: Option<String>,
+}
+
+/// Generate images for `prompt` and save them in `options.dir`
+pub fn generate(
+    api: &ApiClient,
+    options: &ImageOptions,
+    prompt: &str,
+) -> Result<Vec<SavedImage>, String> {
+    let request = ImageRequest {
+        model: &options.model,
+        prompt,
+        n: options.n,
+        size: &options.size,
+        quality: options.quality.as_deref(),
+        style: options.style.as_deref(),
+        response_format: if options.model.starts_with("gpt-image") {
+            None
+        } else {
+            Some("b64_json")
+        },
+    };
+    let response: ImageResponse = api
+        .post_json("/images/generations", &request)
+        .map_err(|e| e.to_string())?;
+    fs::create_dir_all(&options.dir).map_err(|err| format!("{}: {err}", options.dir.display()))?;
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut result = Vec::new();
+    for (i, data) in response.data.into_iter().enumerate() {
+        let encoded = data
+            .b64_json
+            .ok_or_else(|| "The response has no image data".to_string())?;
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|err| format!("Bad image data: {err}"))?;
+        let path = options.dir.join(file_name(prompt, stamp, i));
+        fs::write(&path, bytes).map_err(|err| format!("{}: {err}", path.display()))?;
+        result.push(SavedImage {
+            path,
+            revised_prompt: data.revised_prompt,
+        });
+    }
+    Ok(result)
+}
+
+/// A file name from the start of `prompt`, safe on any file system:
+/// lower case letters, digits and '-'.  `stamp` and `i` make it unique
+pub fn file_name(prompt: &str, stamp: u64, i: usize) -> String {
+    let mut name = String::new();
+    for c in prompt.chars() {
+        if name.len() >= NAME_LENGTH {
+            break;
+        }
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let name = name.trim_end_matches('-');
+    let name = if name.is_empty() { "image" } else { name };
+    format!("{name}-{stamp}-{i}.png")
+}
+
+/// Generate images for `prompt`, display where they were saved, and
+/// record them in the session log.  For the `image` subcommand and
+/// `> image`
+pub fn run_image(
+    api: &ApiClient,
+    options: &ImageOptions,
+    prompt: &str,
+    session_log: &mut SessionLog,
+) -> Result<(), String> {
+    for image in generate(api, options, prompt)? {
+        println!("Saved {}", image.path.display());
+        if let Some(revised_prompt) = image.revised_prompt.as_deref() {
+            println!("Revised prompt: {revised_prompt}");
+        }
+        session_log.record(&SessionEvent::Image {
+            prompt,
+            revised_prompt: image.revised_prompt.as_deref(),
+            path: &image.path.display().to_string(),
+            model: &options.model,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn file_names_are_safe() {
+        assert_eq!(
+            file_name("A cat, on ../../etc/passwd!", 7, 0),
+            "a-cat-on-etc-passwd-7-0.png"
+        );
+        assert_eq!(file_name("   ???", 7, 1), "image-7-1.png");
+        assert!(file_name(&"x".repeat(100), 7, 2).len() < NAME_LENGTH + 20);
+    }
+}
@@ -0,0 +1,315 @@
+//! Structured output.  A JSON schema is sent as the `response_format`
+//! and the answer checked against it locally.  The checking covers the
+//! parts of JSON Schema that OpenAI's structured outputs use: `type`,
+//! `enum`, `const`, `properties`, `required`, `additionalProperties`,
+//! `items`, `anyOf`/`oneOf`/`allOf`, `$ref` into the same document, and
+//! the numeric, length and size limits.  `pattern` and `format` are not
+//! checked
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// The `json_schema` member of a `response_format`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl JsonSchemaFormat {
+    /// Read a schema from `path`.  The file is either a bare schema, named
+    /// after the file, or an object with `name`, `schema` and `strict`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        let value: Value =
+            serde_json::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+        if value.get("schema").is_some() && value.get("name").is_some() {
+            return serde_json::from_value(value)
+                .map_err(|err| format!("{}: {err}", path.display()));
+        }
+        let name: String = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Ok(Self {
+            name,
+            schema: value,
+            strict: Some(true),
+        })
+    }
+
+    /// Parse `text` as JSON and check it against the schema
+    pub fn check(&self, text: &str) -> Result<Value, String> {
+        let instance: Value =
+            serde_json::from_str(text.trim()).map_err(|err| format!("Not JSON: {err}"))?;
+        validate(&self.schema, &instance)?;
+        Ok(instance)
+    }
+}
+
+/// Check `instance` against `schema`.  The error lists every failure,
+/// each with the JSON pointer to where it is
+pub fn validate(schema: &Value, instance: &Value) -> Result<(), String> {
+    let mut errors = Vec::new();
+    check(schema, schema, instance, "", &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+fn type_matches(name: &str, instance: &Value) -> bool {
+    match name {
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "boolean" => instance.is_boolean(),
+        "null" => instance.is_null(),
+        "number" => instance.is_number(),
+        "integer" => {
+            instance.is_i64()
+                || instance.is_u64()
+                || instance.as_f64().map(|f| f.fract() == 0.0).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Resolve a reference like "#/$defs/step" against the root schema
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    reference
+        .strip_prefix('#')
+        .and_then(|pointer| root.pointer(pointer))
+}
+
+fn check(root: &Value, schema: &Value, instance: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{path}: not allowed"));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve(root, reference) {
+            Some(target) => check(root, target, instance, path, errors),
+            None => errors.push(format!("{path}: cannot resolve {reference}")),
+        }
+    }
+    match schema.get("type") {
+        Some(Value::String(name)) if !type_matches(name, instance) => {
+            errors.push(format!("{path}: expected {name}, found {instance}"));
+            return;
+        }
+        Some(Value::Array(names))
+            if !names
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|name| type_matches(name, instance)) =>
+        {
+            errors.push(format!(
+                "{path}: expected one of {names:?}, found {instance}"
+            ));
+            return;
+        }
+        _ => (),
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(instance) {
+            errors.push(format!("{path}: {instance} is not one of {values:?}"));
+        }
+    }
+    if let Some(value) = schema.get("const") {
+        if value != instance {
+            errors.push(format!("{path}: expected {value}, found {instance}"));
+        }
+    }
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for s in all.iter() {
+            check(root, s, instance, path, errors);
+        }
+    }
+    for (keyword, exactly_one) in [("anyOf", false), ("oneOf", true)] {
+        if let Some(Value::Array(options)) = schema.get(keyword) {
+            let matching = options
+                .iter()
+                .filter(|s| {
+                    let mut e = Vec::new();
+                    check(root, s, instance, path, &mut e);
+                    e.is_empty()
+                })
+                .count();
+            if matching == 0 || (exactly_one && matching > 1) {
+                errors.push(format!("{path}: {matching} of the {keyword} schemas match"));
+            }
+        }
+    }
+    match instance {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        errors.push(format!("{path}: missing required property {name:?}"));
+                    }
+                }
+            }
+            for (name, value) in object.iter() {
+                let child = format!("{path}/{name}");
+                match properties.and_then(|p| p.get(name)) {
+                    Some(s) => check(root, s, value, &child, errors),
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            check(root, additional, value, &child, errors)
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(s) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(root, s, item, &format!("{path}/{i}"), errors);
+                }
+            }
+            limit(
+                schema.get("minItems"),
+                items.len(),
+                path,
+                "items",
+                true,
+                errors,
+            );
+            limit(
+                schema.get("maxItems"),
+                items.len(),
+                path,
+                "items",
+                false,
+                errors,
+            );
+        }
+        Value::String(s) => {
+            let length = s.chars().count();
+            limit(
+                schema.get("minLength"),
+                length,
+                path,
+                "characters",
+                true,
+                errors,
+            );
+            limit(
+                schema.get("maxLength"),
+                length,
+                path,
+                "characters",
+                false,
+                errors,
+            );
+        }
+        Value::Number(number) => {
+            let x = number.as_f64().unwrap_or(0.0);
+            let bound = |k: &str| schema.get(k).and_then(Value::as_f64);
+            if let Some(min) = bound("minimum") {
+                if x < min {
+                    errors.push(format!("{path}: {x} is less than {min}"));
+                }
+            }
+            if let Some(max) = bound("maximum") {
+                if x > max {
+                    errors.push(format!("{path}: {x} is more than {max}"));
+                }
+            }
+            if let Some(min) = bound("exclusiveMinimum") {
+                if x <= min {
+                    errors.push(format!("{path}: {x} is not more than {min}"));
+                }
+            }
+            if let Some(max) = bound("exclusiveMaximum") {
+                if x >= max {
+                    errors.push(format!("{path}: {x} is not less than {max}"));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn limit(
+    bound: Option<&Value>,
+    size: usize,
+    path: &str,
+    what: &str,
+    minimum: bool,
+    errors: &mut Vec<String>,
+) {
+    if let Some(bound) = bound.and_then(Value::as_u64) {
+        let bound = bound as usize;
+        if minimum && size < bound {
+            errors.push(format!("{path}: {size} {what}, at least {bound} needed"));
+        } else if !minimum && size > bound {
+            errors.push(format!("{path}: {size} {what}, at most {bound} allowed"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn validate_object() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}},
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "$defs": {"tag": {"enum": ["a", "b"]}}
+        });
+        assert!(validate(&schema, &json!({"name": "x", "age": 3, "tags": ["a"]})).is_ok());
+        let err = validate(
+            &schema,
+            &json!({"name": "", "age": -1.5, "tags": ["c"], "more": 1}),
+        )
+        .unwrap_err();
+        assert!(err.contains("/name: 0 characters"));
+        assert!(err.contains("/age: expected integer"));
+        assert!(err.contains("/tags/0: \"c\" is not one of"));
+        assert!(err.contains("/more: not allowed"));
+        assert!(validate(&schema, &json!({"name": "x"}))
+            .unwrap_err()
+            .contains("missing required property \"age\""));
+    }
+
+    #[test]
+    fn check_text() {
+        let format = JsonSchemaFormat {
+            name: "n".to_string(),
+            schema: json!({"anyOf": [{"type": "string"}, {"type": "null"}]}),
+            strict: None,
+        };
+        assert_eq!(format.check(" null ").unwrap(), Value::Null);
+        assert!(format.check("3").is_err());
+        assert!(format.check("not json").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Per-token log probabilities returned by the completions end point
+//! when `logprobs` is set.  See
+//! https://platform.openai.com/docs/api-reference/completions/create#completions/create-logprobs
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Tokens with a probability below this are highlighted
+pub const LOW_CONFIDENCE: f32 = 0.5;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogProbs {
+    pub tokens: Vec<String>,
+    /// `None` for tokens the model did not score
+    pub token_logprobs: Vec<Option<f32>>,
+    /// The most likely tokens at each position, with their log probabilities
+    #[serde(default)]
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+}
+
+impl LogProbs {
+    /// One line per token: probability, the token, and the most likely
+    /// alternatives.  Tokens below `LOW_CONFIDENCE` are in red
+    pub fn format(&self) -> String {
+        let mut result = String::new();
+        for (i, token) in self.tokens.iter().enumerate() {
+            let probability = match self.token_logprobs.get(i) {
+                Some(Some(lp)) => lp.exp(),
+                _ => continue,
+            };
+            let mut line = format!("{:>7.2}% {:?}", probability * 100.0, token);
+            if let Some(Some(top)) = self.top_logprobs.get(i) {
+                let mut top: Vec<(&String, &f32)> =
+                    top.iter().filter(|(t, _)| *t != token).collect();
+                top.sort_by(|a, b| b.1.total_cmp(a.1));
+                let alternatives: Vec<String> = top
+                    .iter()
+                    .map(|(t, lp)| format!("{:?} {:.2}%", t, lp.exp() * 100.0))
+                    .collect();
+                if !alternatives.is_empty() {
+                    line.push_str(&format!("  ({})", alternatives.join(", ")));
+                }
+            }
+            if probability < LOW_CONFIDENCE {
+                line = format!("\x1b[1;31m{line}\x1b[0m");
+            }
+            result.push_str(&line);
+            result.push('\n');
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn format_highlights_low_confidence() {
+        let logprobs: LogProbs = serde_json::from_str(
+            r#"{"tokens": ["Yes", "."],
+                "token_logprobs": [-0.01, -2.0],
+                "top_logprobs": [{"Yes": -0.01, "No": -5.0}, null],
+                "text_offset": [0, 3]}"#,
+        )
+        .unwrap();
+        let formatted = logprobs.format();
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"Yes\"") && lines[0].contains("\"No\""));
+        assert!(!lines[0].starts_with("\x1b[1;31m"));
+        assert!(lines[1].starts_with("\x1b[1;31m"));
+    }
+}
@@ -1,26 +1,69 @@
 #![allow(dead_code)]
 // use std::io;
 // TODO:  Make time out a parameter.  Report time out in "> p".
-use clap::Parser;
-use reqwest::blocking::ClientBuilder;
-use reqwest::StatusCode;
+use clap::{Parser, Subcommand};
 use rustyline::completion::FilenameCompleter;
-use rustyline::highlight::{Highlighter, MatchingBracketHighlighter};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
 use rustyline::hint::HistoryHinter;
+use rustyline::history::DefaultHistory;
 use rustyline::validate::MatchingBracketValidator;
 use rustyline::Validator;
 use rustyline::{Cmd, CompletionType, Config, EditMode, Editor, Event, EventHandler, KeyEvent};
 use rustyline::{Completer, Helper, Hinter};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow::{self, Borrowed, Owned};
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Write; //::{Editor};
+use std::path::{Path, PathBuf};
+mod api_client;
+mod api_key;
+mod audio;
+mod backend;
+mod batch;
+mod cache;
+mod cassette;
+mod chat;
+mod compare;
+mod completions;
+mod config;
+mod conversation;
+mod doctor;
+mod embeddings;
+mod eval;
+mod files;
+mod finetune;
 mod get_models;
+mod images;
+mod json_schema;
+mod logprobs;
+#[cfg(any(test, feature = "mock"))]
+mod mock_server;
 mod model_example_data;
+mod moderation;
+mod network;
+mod profiles;
+mod rag;
+mod sampling;
+mod script;
+mod session_log;
+mod tools;
+mod usage;
+mod vision;
+mod workspace;
+use api_client::ApiClient;
+use api_key::ApiKey;
+use backend::Backend;
+use chat::ImageUrl;
+use completions::{Choice, CompletionRequestInfo};
+use conversation::{Conversation, Turn};
+use json_schema::JsonSchemaFormat;
 #[cfg(test)]
 use model_example_data::ModelExampleData;
+use sampling::{ResponseFormat, Sampling};
+use session_log::{SessionEvent, SessionLog};
 /// `MyHelper` is copied from the examples in `RustyLine` crate
 #[derive(Helper, Completer, Hinter, Validator)]
 struct MyHelper {
@@ -56,14 +99,17 @@ impl Highlighter for MyHelper {
         self.highlighter.highlight(line, pos)
     }
 
-    fn highlight_char(&self, line: &str, pos: usize) -> bool {
-        self.highlighter.highlight_char(line, pos)
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.highlighter.highlight_char(line, pos, kind)
     }
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Arguments {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// The model to use
     #[arg(long)]
     model: Option<String>,
@@ -76,42 +122,502 @@ struct Arguments {
     #[arg(long, default_value_t = 0.9)]
     temperature: f32,
 
-    /// The secret key
+    /// The secret key.  Visible in `ps` and shell history: prefer
+    /// $OPENAI_API_KEY, `--api-key-file` or the `api_key` section of the
+    /// configuration
+    #[arg(long)]
+    api_key: Option<ApiKey>,
+
+    /// A file with the secret key, that only you can read
     #[arg(long)]
-    api_key: Option<String>,
+    api_key_file: Option<PathBuf>,
+
+    /// Use the key, organization and project of this profile in the
+    /// configuration
+    #[arg(long, conflicts_with_all = ["api_key", "api_key_file"])]
+    profile: Option<String>,
+
+    /// How many answers to ask for.  If more than one, choose between them
+    #[arg(long, default_value_t = 1)]
+    n: u32,
+
+    /// Display the probabilities of each token in the answer, and the
+    /// `LOGPROBS` most likely alternatives: at most 5 for completion
+    /// models, 20 for chat models
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=20))]
+    logprobs: Option<u32>,
 
     #[arg(long)]
     start_prompt: Option<String>,
+
+    /// The configuration file
+    #[arg(long, default_value = "config.json")]
+    config: PathBuf,
+
+    /// Nucleus sampling: consider tokens in the top `TOP_P` probability mass
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Penalise tokens that have appeared at all (-2 to 2)
+    #[arg(long)]
+    presence_penalty: Option<f32>,
+
+    /// Penalise tokens by how often they have appeared (-2 to 2)
+    #[arg(long)]
+    frequency_penalty: Option<f32>,
+
+    /// A sequence where the model will stop.  Up to four
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Seed for (mostly) deterministic sampling
+    #[arg(long)]
+    seed: Option<i64>,
+
+    /// Bias a token: `TOKEN:BIAS`, bias from -100 to 100.  May be repeated
+    #[arg(long)]
+    logit_bias: Vec<String>,
+
+    /// The end user, sent to OpenAI to help detect abuse
+    #[arg(long)]
+    user: Option<String>,
+
+    /// `text` or `json_object`
+    #[arg(long)]
+    response_format: Option<String>,
+
+    /// Ask for answers that match the JSON schema in this file, and
+    /// check that they do
+    #[arg(long)]
+    json_schema: Option<PathBuf>,
+
+    /// How many times to ask again when an answer does not match the
+    /// JSON schema
+    #[arg(long, default_value_t = 3)]
+    json_retries: u32,
+
+    /// One-shot mode: send this prompt, display the answer and exit
+    #[arg(long)]
+    prompt: Option<String>,
+
+    /// In one-shot mode write the answer to this file instead
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Use the chat completions end point.  This is the default for
+    /// chat models: gpt-*, o1, o3...
+    #[arg(long)]
+    chat: bool,
+
+    /// Let chat models read, search and (with approval) change files,
+    /// and run commands, in this directory
+    #[arg(long)]
+    workspace: Option<PathBuf>,
+
+    /// Add excerpts from the text files in this directory, found by
+    /// embedding the prompt, to each prompt
+    #[arg(long)]
+    rag: Option<PathBuf>,
+
+    /// How many excerpts to add to each prompt with `--rag`
+    #[arg(long, default_value_t = 4)]
+    rag_k: usize,
+
+    /// Detail for images attached with `@path` or `> img`: low, high or auto
+    #[arg(long)]
+    image_detail: Option<String>,
+
+    /// Scale attached images down so neither side is more than this
+    #[arg(long)]
+    max_image_side: Option<u32>,
+
+    /// Where the API is.  Defaults to $OPENAI_BASE_URL, or OpenAI
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// The proxy for every request.  Defaults to $HTTPS_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// The user to authenticate to the proxy as.  The password is in
+    /// $PROXY_PASSWORD, or asked for
+    #[arg(long)]
+    proxy_user: Option<String>,
+
+    /// Hosts not to use the proxy for, separated by commas.  Defaults to
+    /// $NO_PROXY
+    #[arg(long)]
+    no_proxy: Option<String>,
+
+    /// PEM certificates to trust as well as the system's
+    #[arg(long)]
+    ca_bundle: Option<PathBuf>,
+
+    /// A PEM client certificate
+    #[arg(long)]
+    client_cert: Option<PathBuf>,
+
+    /// The client certificate's PKCS#8 PEM key, if not in the certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Record every request and response in this directory
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer requests from the recordings in this directory, instead
+    /// of the API.  A request that was not recorded is an error
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Read prompts, `>` commands and expectations from this file
+    /// instead of the keyboard.  Exits with an error if an expectation
+    /// fails
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Keep answers in an on-disk cache, and use them when the same
+    /// request is made again.  The `cache` section of the configuration
+    /// sets where, for how long and how big
+    #[arg(long)]
+    cache: bool,
+
+    /// Do not use the cache, even if the configuration enables it
+    #[arg(long, conflicts_with = "cache")]
+    no_cache: bool,
+
+    /// Check prompts with the moderation end point before sending them.
+    /// The `moderation` section of the configuration sets thresholds
+    #[arg(long)]
+    moderate: bool,
+
+    /// The ledger the tokens each response used are added to, and
+    /// `usage` reads.  The `usage` section of the configuration sets it
+    /// too.  Default: usage.jsonl
+    #[arg(long, global = true)]
+    ledger: Option<PathBuf>,
+
+    /// Do not add the tokens used to the ledger
+    #[arg(long)]
+    no_ledger: bool,
 }
 
-/// Response for a completions request.  See
-/// https://platform.openai.com/docs/api-reference/completions/create
-#[derive(Debug, Serialize, Deserialize)]
-struct CompletionRequestInfo {
-    #[serde(skip_serializing)]
-    id: String,
-    #[serde(skip_serializing)]
-    object: String,
-    #[serde(skip_serializing)]
-    choices: Vec<Choice>,
-    #[serde(skip_deserializing)]
-    prompt: String,
-    model: String,
-    #[serde(skip_deserializing)]
-    temperature: f32,
-    #[serde(skip_deserializing)]
-    max_tokens: u32,
+/// Things to do instead of chatting
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Embed files, directories or text and add them to the index
+    Embed {
+        /// Files, or directories of text files
+        paths: Vec<PathBuf>,
+
+        /// Text to embed, as well as or instead of files
+        #[arg(long)]
+        text: Option<String>,
+
+        /// The index file
+        #[arg(long, default_value = "index.json")]
+        index: PathBuf,
+
+        /// The embedding model.  Defaults to the one the index was made with
+        #[arg(long)]
+        embedding_model: Option<String>,
+
+        /// Lines of text in each chunk
+        #[arg(long, default_value_t = 40)]
+        chunk_lines: usize,
+    },
+    /// Find the chunks in the index most like the query
+    Search {
+        query: String,
+
+        /// The index file
+        #[arg(long, default_value = "index.json")]
+        index: PathBuf,
+
+        /// How many chunks to display
+        #[arg(short, default_value_t = 5)]
+        k: usize,
+    },
+    /// Generate images and save them as PNG files
+    Image {
+        prompt: String,
+
+        /// The image model
+        #[arg(long, default_value = images::DEFAULT_MODEL)]
+        image_model: String,
+
+        /// 1024x1024, 1792x1024 or 1024x1792 for dall-e-3
+        #[arg(long, default_value = "1024x1024")]
+        size: String,
+
+        /// standard or hd for dall-e-3
+        #[arg(long)]
+        quality: Option<String>,
+
+        /// vivid or natural for dall-e-3
+        #[arg(long)]
+        style: Option<String>,
+
+        /// How many images.  dall-e-3 only makes one
+        #[arg(long, default_value_t = 1)]
+        n: u32,
+
+        /// Where to save the images
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Transcribe an audio file
+    Transcribe {
+        file: PathBuf,
+
+        /// The transcription model
+        #[arg(long, default_value = audio::TRANSCRIPTION_MODEL)]
+        audio_model: String,
+
+        /// The language of the audio, e.g. en
+        #[arg(long)]
+        language: Option<String>,
+
+        /// Text to guide the transcription
+        #[arg(long)]
+        audio_prompt: Option<String>,
+
+        /// json, text, srt, verbose_json or vtt
+        #[arg(long)]
+        format: Option<String>,
+
+        /// Write the transcription to this file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Say some text and save it as an audio file
+    Speak {
+        text: String,
+
+        /// The speech model
+        #[arg(long, default_value = audio::SPEECH_MODEL)]
+        audio_model: String,
+
+        /// alloy, echo, fable, onyx, nova or shimmer
+        #[arg(long, default_value = "alloy")]
+        voice: String,
+
+        /// mp3, opus, aac, flac, wav or pcm.  Defaults to the extension of the file
+        #[arg(long)]
+        format: Option<String>,
+
+        /// 0.25 to 4.0
+        #[arg(long)]
+        speed: Option<f32>,
+
+        /// The audio file.  Defaults to speech.mp3
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Manage files uploaded for fine-tuning, batches...
+    Files {
+        #[command(subcommand)]
+        command: FilesCommand,
+    },
+    /// Check training data, and create and follow fine-tuning jobs
+    Finetune {
+        #[command(subcommand)]
+        command: FinetuneCommand,
+    },
+    /// Send requests in a file with the Batch API, and fetch the results
+    Batch {
+        #[command(subcommand)]
+        command: BatchCommand,
+    },
+    /// Ask several models the same prompt at once and display the
+    /// answers side by side, with latency, tokens and cost
+    Compare {
+        prompt: String,
+
+        /// The models to ask
+        #[arg(required = true)]
+        models: Vec<String>,
+    },
+    /// Run a dataset of prompts, with the answers expected, against
+    /// models and parameters, and compare how they score
+    Eval {
+        /// JSONL: `input`, `expected` and optionally `id` and `scorer` on each line
+        dataset: PathBuf,
+
+        /// A model and parameters: "gpt-4o-mini temperature=0".  May be
+        /// repeated.  Defaults to the model and parameters given
+        #[arg(long)]
+        variant: Vec<String>,
+
+        /// exact, contains, regex, json or judge, for cases without a scorer
+        #[arg(long, default_value = "exact")]
+        scorer: String,
+
+        /// The model that grades answers for the judge scorer
+        #[arg(long, default_value = "gpt-4o-mini")]
+        judge_model: String,
+
+        /// The judge's instructions, with {input}, {expected} and {answer}
+        #[arg(long)]
+        judge_prompt: Option<PathBuf>,
+
+        /// The score, from 0 to 1, a case needs to pass
+        #[arg(long, default_value_t = 0.7)]
+        pass_score: f64,
+
+        /// Where to save the JSON report
+        #[arg(long, default_value = "eval-report.json")]
+        report: PathBuf,
+    },
+    /// Display the tokens used, and their cost, by profile, project and
+    /// model, from the ledger
+    Usage,
+    /// Display the network settings and check the API can be reached
+    Doctor,
+    /// Pretend to be the OpenAI API, for testing.  Displays its URL
+    #[cfg(feature = "mock")]
+    MockServer {
+        /// 0 for any free port
+        #[arg(long, default_value_t = 0)]
+        port: u16,
+
+        /// Scripted responses.  See `mock_server`
+        #[arg(long)]
+        fixtures: Option<PathBuf>,
+    },
 }
-impl CompletionRequestInfo {
-    fn new(prompt: String, model: String, temperature: f32, max_tokens: u32) -> Self {
-        Self {
-            choices: Vec::new(),
-            id: String::new(),
-            object: String::new(),
-            prompt,
-            model,
-            temperature,
-            max_tokens,
+
+#[derive(Subcommand, Debug)]
+enum FilesCommand {
+    /// List the files
+    List {
+        /// Only files for this purpose
+        #[arg(long)]
+        purpose: Option<String>,
+    },
+    /// Upload a file
+    Upload {
+        file: PathBuf,
+
+        /// fine-tune, batch, assistants, vision or user_data
+        #[arg(long)]
+        purpose: String,
+    },
+    /// Download the contents of a file
+    Download {
+        id: String,
+
+        /// Where to save it.  Defaults to the file's name
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Delete a file
+    Delete { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum FinetuneCommand {
+    /// Check training data, JSONL, without uploading it
+    Validate { data: PathBuf },
+    /// Start a fine-tuning job
+    Create {
+        /// Training data: a JSONL file, checked and uploaded, or the id of an uploaded file
+        training: String,
+
+        /// The model to fine-tune
+        #[arg(long)]
+        base_model: String,
+
+        /// Validation data: a JSONL file or the id of an uploaded file
+        #[arg(long)]
+        validation: Option<String>,
+
+        /// Added to the name of the fine-tuned model
+        #[arg(long)]
+        suffix: Option<String>,
+
+        /// How many times to go through the training data
+        #[arg(long)]
+        epochs: Option<u32>,
+    },
+    /// Display a job's events, and follow it until it finishes
+    Status {
+        id: String,
+
+        /// Display the events so far and exit
+        #[arg(long)]
+        no_follow: bool,
+    },
+    /// Cancel a job
+    Cancel { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum BatchCommand {
+    /// Upload a JSONL file of requests and start a batch
+    Submit { input: PathBuf },
+    /// Display a batch's progress, and follow it until it finishes
+    Status {
+        id: String,
+
+        /// Display the progress and exit
+        #[arg(long)]
+        no_follow: bool,
+    },
+    /// Download the results and join them to the requests
+    Fetch {
+        id: String,
+
+        /// The requests.  Downloaded if not given
+        #[arg(long)]
+        input: Option<PathBuf>,
+
+        /// The merged results.  Defaults to <id>-results.jsonl
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+impl Arguments {
+    /// The sampling parameters set on the command line
+    fn sampling(&self) -> Result<Sampling, String> {
+        let mut sampling = Sampling {
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            seed: self.seed,
+            user: self.user.clone(),
+            ..Default::default()
+        };
+        for stop in self.stop.iter() {
+            sampling
+                .stop
+                .get_or_insert_with(Vec::new)
+                .append(&mut sampling::parse_stop(stop)?);
+        }
+        for bias in self.logit_bias.iter() {
+            sampling
+                .logit_bias
+                .get_or_insert_with(Default::default)
+                .append(&mut sampling::parse_logit_bias(bias)?);
+        }
+        if let Some(response_format) = self.response_format.as_deref() {
+            sampling.set("response_format", response_format)?;
+        }
+        sampling.validate()?;
+        Ok(sampling)
+    }
+
+    /// The network settings on the command line
+    fn network(&self) -> network::NetworkConfig {
+        network::NetworkConfig {
+            proxy: self.proxy.clone(),
+            proxy_user: self.proxy_user.clone(),
+            no_proxy: self.no_proxy.clone(),
+            ca_bundle: self.ca_bundle.clone(),
+            client_cert: self.client_cert.clone(),
+            client_key: self.client_key.clone(),
+            ..Default::default()
         }
     }
 }
@@ -140,20 +646,23 @@ struct ModelRequestInfo {
     data: Vec<ModelData>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Choice {
-    text: String,
-    logprobs: Option<Vec<f32>>,
-    finish_reason: String,
-    index: i32,
+/// What to send to the model next
+enum Pending {
+    /// A new prompt
+    Prompt {
+        prompt: String,
+        images: Vec<ImageUrl>,
+    },
+    /// Ask for more of the last answer, that ran out of tokens
+    Continue(Turn),
 }
 
 fn justify_string(s: &str) -> String {
     let mut result = String::new();
     let mut line_length = 0;
-    let mut words = s.split_whitespace();
+    let words = s.split_whitespace();
 
-    while let Some(word) = words.next() {
+    for word in words {
         let word_length = word.len();
 
         if line_length + word_length + 1 > 80 {
@@ -171,29 +680,281 @@ fn justify_string(s: &str) -> String {
     result
 }
 
+/// Check each of the `choices` before any is shown, and drop those that
+/// are blocked.  Their verdicts are added to `verdicts`.  Returns the
+/// first answer that was blocked
+fn withhold_blocked(
+    backend: &Backend,
+    choices: &mut Vec<Choice>,
+    verdicts: &mut Vec<moderation::Verdict>,
+) -> Option<String> {
+    let mut blocked = None;
+    choices.retain(|choice| {
+        let Some(verdict) = backend.moderate("answer", choice.text.trim_start()) else {
+            return true;
+        };
+        let pass = verdict.action != moderation::Action::Block;
+        if !pass {
+            blocked.get_or_insert_with(|| choice.text.trim_start().to_string());
+        }
+        verdicts.push(verdict);
+        pass
+    });
+    blocked
+}
+
+/// Read the answer to `prompt`: from the script, if there is one, or
+/// the line editor
+fn read_answer(
+    rl: &mut Editor<MyHelper, DefaultHistory>,
+    script: &mut Option<script::Script>,
+    prompt: &str,
+) -> rustyline::Result<String> {
+    match script.as_mut() {
+        Some(script) => match script.next_answer(prompt) {
+            Some(line) => {
+                println!("{prompt}{line}");
+                Ok(line)
+            }
+            None => Err(ReadlineError::Eof),
+        },
+        None => rl.readline(prompt),
+    }
+}
+
+/// Display the numbered `choices` and ask the user which to use.
+/// Returns an index into `choices`
+fn choose_answer(
+    rl: &mut Editor<MyHelper, DefaultHistory>,
+    script: &mut Option<script::Script>,
+    choices: &[Choice],
+) -> usize {
+    for choice in choices.iter() {
+        println!("[{}]", choice.index + 1);
+        for s in choice.text.trim_start().split_terminator('\n') {
+            println!("{}", justify_string(s));
+        }
+    }
+    // `index` is the position in the list the API returned, which may
+    // not be the position in `choices` if some were withheld
+    let numbers = choices
+        .iter()
+        .map(|c| (c.index + 1).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let p = format!("Choose {numbers}: ");
+    rl.helper_mut().expect("No helper").colored_prompt = format!("\x1b[1;33m{p}\x1b[0m");
+    loop {
+        let line = match read_answer(rl, script, &p) {
+            Ok(line) => line,
+            // Take the first on interrupt
+            Err(_) => return 0,
+        };
+        let chosen = line
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| choices.iter().position(|c| c.index as usize + 1 == i));
+        match chosen {
+            Some(chosen) => return chosen,
+            None => println!("Enter one of {numbers}"),
+        }
+    }
+}
+
+/// Ask which of the compared answers to continue with.  `None` keeps
+/// the answer there was
+fn choose_compared<'a>(
+    rl: &mut Editor<MyHelper, DefaultHistory>,
+    script: &mut Option<script::Script>,
+    outcomes: &'a [compare::Outcome],
+) -> Option<&'a compare::Outcome> {
+    let p = format!(
+        "Continue with 1-{}, or Enter to keep the last answer: ",
+        outcomes.len()
+    );
+    rl.helper_mut().expect("No helper").colored_prompt = format!("\x1b[1;33m{p}\x1b[0m");
+    loop {
+        let line = match read_answer(rl, script, &p) {
+            Ok(line) if !line.trim().is_empty() => line,
+            _ => return None,
+        };
+        match line.trim().parse::<usize>() {
+            Ok(i) if (1..=outcomes.len()).contains(&i) && outcomes[i - 1].answer().is_some() => {
+                return Some(&outcomes[i - 1]);
+            }
+            _ => println!("Enter the number of an answer"),
+        }
+    }
+}
+
+/// Ask a yes/no question on the terminal, without the line editor
+fn confirm_stdin(question: &str) -> bool {
+    print!("{question}");
+    _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => answer.trim().eq_ignore_ascii_case("y"),
+        Err(_) => false,
+    }
+}
+
+/// Ask a yes/no question with the line editor, or the script
+fn confirm(
+    rl: &mut Editor<MyHelper, DefaultHistory>,
+    script: &mut Option<script::Script>,
+    question: &str,
+) -> bool {
+    rl.helper_mut().expect("No helper").colored_prompt = format!("\x1b[1;33m{question}\x1b[0m");
+    match read_answer(rl, script, question) {
+        Ok(answer) => answer.trim().eq_ignore_ascii_case("y"),
+        Err(_) => false,
+    }
+}
+
+/// For a response format, or JSON schema, asked of a `model` that uses
+/// the completions end point
+fn no_response_format(model: &str) -> String {
+    format!(
+        "{model} uses the completions end point, which cannot take a response \
+         format or JSON schema.  Use a chat model, or --chat"
+    )
+}
+
+/// Answers are only checked against the schema while it is the
+/// response format
+fn drop_schema(backend: &mut Backend, sampling: &Sampling) {
+    if !matches!(
+        sampling.response_format,
+        Some(ResponseFormat::JsonSchema { .. })
+    ) && backend.schema.take().is_some()
+    {
+        println!("Answers are no longer checked against the JSON schema");
+    }
+}
+
+/// The key from the command line, the environment or the `api_key`
+/// section of the configuration, with no profile
+fn default_credentials(
+    cmd_line_opts: &Arguments,
+    key_config: &api_key::KeyConfig,
+) -> Result<profiles::Credentials, String> {
+    api_key::resolve(
+        cmd_line_opts.api_key.as_ref(),
+        cmd_line_opts.api_key_file.as_deref(),
+        key_config,
+    )
+    .map(|(key, _)| profiles::Credentials::from_env(key))
+}
+
+/// The credentials for `> key name`.  `default` is those the session
+/// started with, unless it started with a profile
+fn switch_credentials(
+    name: &str,
+    startup: &profiles::Credentials,
+    cmd_line_opts: &Arguments,
+    profiles: &BTreeMap<String, profiles::Profile>,
+    key_config: &api_key::KeyConfig,
+) -> Result<profiles::Credentials, String> {
+    if name != profiles::DEFAULT {
+        profiles::load(profiles, name)
+    } else if startup.profile == profiles::DEFAULT {
+        Ok(startup.clone())
+    } else {
+        default_credentials(cmd_line_opts, key_config)
+    }
+}
+
+/// Where the replies to prompts are appended
+const REPLY_FILE: &str = "reply.txt";
+
+/// Where the events of a session are appended
+const SESSION_LOG: &str = "session.jsonl";
+
+fn open_session_log() -> Result<SessionLog, String> {
+    SessionLog::open(SESSION_LOG).map_err(|err| format!("{SESSION_LOG}: {err}"))
+}
+
 fn main() -> rustyline::Result<()> {
     // Get the command line options
     let default_model = "text-davinci-003".to_string();
     let cmd_line_opts = Arguments::parse();
-    let mut options = OpenOptions::new();
-    let mut conversation_record_file: File = options
-        .write(true)
-        .append(true)
-        .create(true)
-        .open("reply.txt")
-        .unwrap();
 
-    let _key_binding: String;
-    let api_key = match cmd_line_opts.api_key.as_deref() {
-        Some(key) => key,
-        None => {
-            _key_binding = env::var("OPENAI_API_KEY").unwrap();
-            _key_binding.as_str()
+    // Commands that are local, and do not need a key
+    let local = match cmd_line_opts.command.as_ref() {
+        Some(Commands::Finetune {
+            command: FinetuneCommand::Validate { data },
+        }) => Some(finetune::run_validate(data)),
+        #[cfg(feature = "mock")]
+        Some(Commands::MockServer { port, fixtures }) => {
+            Some(mock_server::run_mock_server(*port, fixtures.as_deref()))
+        }
+        _ => None,
+    };
+    if let Some(result) = local {
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // The configuration file
+    let configuration = match config::Config::load(&cmd_line_opts.config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Configuration: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let ledger = cmd_line_opts
+        .ledger
+        .clone()
+        .unwrap_or_else(|| configuration.usage.ledger.clone());
+    if let Some(Commands::Usage) = cmd_line_opts.command {
+        if let Err(err) = usage::run_usage(&ledger) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let credentials = match cmd_line_opts.profile.as_deref() {
+        Some(name) if name != profiles::DEFAULT => profiles::load(&configuration.profiles, name),
+        _ => default_credentials(&cmd_line_opts, &configuration.api_key),
+    };
+
+    // The HTTP client's proxy and certificates
+    let network = match network::Network::new(&cmd_line_opts.network(), &configuration.network) {
+        Ok(network) => network,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let base_url = cmd_line_opts
+        .base_url
+        .clone()
+        .or_else(|| env::var("OPENAI_BASE_URL").ok())
+        .unwrap_or_else(|| api_client::OPENAI_URL.to_string());
+    if let Some(Commands::Doctor) = cmd_line_opts.command {
+        if let Err(err) = doctor::run_doctor(&network, &base_url, credentials) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let credentials = match credentials {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
         }
     };
 
     // The initialisation prompt, passed to the OpenAI chat-bot
-    let _prompt_binding: String;
     let initial_prompt = cmd_line_opts
         .start_prompt
         .as_deref()
@@ -205,6 +966,392 @@ fn main() -> rustyline::Result<()> {
     };
     let tokens: u32 = cmd_line_opts.max_tokens;
     let temperature: f32 = cmd_line_opts.temperature;
+    let mut n: u32 = cmd_line_opts.n;
+
+    // Sampling parameters from the configuration file, overridden by
+    // the command line
+    let mut sampling = configuration.sampling;
+    match cmd_line_opts.sampling() {
+        Ok(s) => sampling.merge(s),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+
+    // Structured output
+    let schema: Option<JsonSchemaFormat> = match cmd_line_opts.json_schema.as_deref() {
+        Some(path) => match JsonSchemaFormat::load(path) {
+            Ok(schema) => {
+                sampling.response_format = Some(ResponseFormat::JsonSchema {
+                    json_schema: schema.clone(),
+                });
+                Some(schema)
+            }
+            Err(err) => {
+                eprintln!("JSON schema: {err}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut request_info =
+        CompletionRequestInfo::new(String::new(), model.to_string(), temperature, tokens);
+    request_info.logprobs = cmd_line_opts.logprobs;
+    request_info.sampling = sampling.clone();
+
+    // The API client. `reqwest`
+    let client = match network.client() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    // The built in tools
+    let workspace = match (cmd_line_opts.workspace.as_ref(), configuration.workspace) {
+        (None, None) => None,
+        (root, config) => {
+            let mut config = config.unwrap_or_default();
+            if root.is_some() {
+                config.root = root.cloned();
+            }
+            match workspace::Workspace::new(config) {
+                Ok(workspace) => Some(workspace),
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    // `> key default` goes back to these
+    let startup_credentials = credentials.clone();
+    let mut api = ApiClient::new(client, credentials.key.clone())
+        .with_credentials(credentials)
+        .with_base_url(&base_url);
+    if configuration.usage.record && !cmd_line_opts.no_ledger {
+        api = api.with_ledger(usage::Ledger::new(&ledger));
+    }
+    let cassette = match (
+        cmd_line_opts.record.as_deref(),
+        cmd_line_opts.replay.as_deref(),
+    ) {
+        (Some(dir), _) => Some(cassette::Cassette::new(dir, cassette::Mode::Record)),
+        (None, Some(dir)) => Some(cassette::Cassette::new(dir, cassette::Mode::Replay)),
+        (None, None) => None,
+    };
+    match cassette {
+        Some(Ok(cassette)) => api = api.with_cassette(cassette),
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        None => (),
+    }
+    let cache_config = match configuration.cache {
+        _ if cmd_line_opts.no_cache => None,
+        Some(config) => Some(config),
+        None => cmd_line_opts.cache.then(cache::CacheConfig::default),
+    };
+    if let Some(config) = cache_config {
+        match cache::ResponseCache::new(&config) {
+            Ok(cache) => api = api.with_cache(cache),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut backend = Backend {
+        api,
+        chat: cmd_line_opts.chat,
+        tools: configuration.tools,
+        workspace,
+        schema,
+        json_retries: cmd_line_opts.json_retries,
+        moderation: configuration.moderation.or_else(|| {
+            cmd_line_opts
+                .moderate
+                .then(moderation::ModerationConfig::default)
+        }),
+    };
+
+    // Structured output is only for chat models
+    if sampling.response_format.is_some() && !backend.uses_chat(model) {
+        eprintln!("{}", no_response_format(model));
+        std::process::exit(1);
+    }
+
+    // The completions end point allows fewer alternatives than chat
+    if cmd_line_opts.logprobs.is_some_and(|l| l > 5) && !backend.uses_chat(model) {
+        eprintln!("--logprobs: at most 5 for completion models");
+        std::process::exit(1);
+    }
+
+    // Images attached to prompts
+    let mut vision_options = vision::VisionOptions {
+        detail: cmd_line_opts.image_detail.clone(),
+        max_side: cmd_line_opts.max_image_side,
+    };
+    if let Some(Err(err)) = vision_options.detail.as_deref().map(vision::check_detail) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+
+    // Subcommands
+    if let Some(command) = cmd_line_opts.command.as_ref() {
+        let result = match command {
+            Commands::Embed {
+                paths,
+                text,
+                index,
+                embedding_model,
+                chunk_lines,
+            } => embeddings::run_embed(
+                &backend.api,
+                paths,
+                text.as_deref(),
+                index,
+                embedding_model.as_deref(),
+                *chunk_lines,
+            ),
+            Commands::Search { query, index, k } => {
+                embeddings::run_search(&backend.api, query, index, *k)
+            }
+            Commands::Image {
+                prompt,
+                image_model,
+                size,
+                quality,
+                style,
+                n,
+                dir,
+            } => {
+                let options = images::ImageOptions {
+                    model: image_model.clone(),
+                    size: size.clone(),
+                    quality: quality.clone(),
+                    n: *n,
+                    style: style.clone(),
+                    dir: dir.clone(),
+                };
+                open_session_log().and_then(|mut session_log| {
+                    images::run_image(&backend.api, &options, prompt, &mut session_log)
+                })
+            }
+            Commands::Transcribe {
+                file,
+                audio_model,
+                language,
+                audio_prompt,
+                format,
+                output,
+            } => {
+                let options = audio::TranscribeOptions {
+                    model: audio_model.clone(),
+                    language: language.clone(),
+                    prompt: audio_prompt.clone(),
+                    response_format: format.clone(),
+                };
+                audio::run_transcribe(&backend.api, file, &options, output.as_deref())
+            }
+            Commands::Speak {
+                text,
+                audio_model,
+                voice,
+                format,
+                speed,
+                output,
+            } => {
+                let options = audio::SpeechOptions {
+                    model: audio_model.clone(),
+                    voice: voice.clone(),
+                    format: format.clone(),
+                    speed: *speed,
+                };
+                audio::run_speak(&backend.api, text, &options, output.as_deref())
+            }
+            Commands::Files { command } => match command {
+                FilesCommand::List { purpose } => files::run_list(&backend.api, purpose.as_deref()),
+                FilesCommand::Upload { file, purpose } => {
+                    files::run_upload(&backend.api, file, purpose)
+                }
+                FilesCommand::Download { id, output } => {
+                    files::run_download(&backend.api, id, output.as_deref())
+                }
+                FilesCommand::Delete { id } => files::run_delete(&backend.api, id),
+            },
+            Commands::Finetune { command } => match command {
+                FinetuneCommand::Validate { data } => finetune::run_validate(data),
+                FinetuneCommand::Create {
+                    training,
+                    base_model,
+                    validation,
+                    suffix,
+                    epochs,
+                } => {
+                    let options = finetune::JobOptions {
+                        model: base_model,
+                        validation: validation.as_deref(),
+                        suffix: suffix.as_deref(),
+                        epochs: *epochs,
+                    };
+                    finetune::run_create(&backend.api, training, &options)
+                }
+                FinetuneCommand::Status { id, no_follow } => {
+                    finetune::run_status(&backend.api, id, !no_follow)
+                }
+                FinetuneCommand::Cancel { id } => finetune::run_cancel(&backend.api, id),
+            },
+            Commands::Compare { prompt, models } => {
+                compare::run_compare(&backend, &request_info, prompt, models)
+            }
+            Commands::Eval {
+                dataset,
+                variant,
+                scorer,
+                judge_model,
+                judge_prompt,
+                pass_score,
+                report,
+            } => {
+                let options = eval::EvalOptions {
+                    variants: variant.clone(),
+                    scorer: scorer.clone(),
+                    judge_model: judge_model.clone(),
+                    judge_prompt: judge_prompt.clone(),
+                    pass_score: *pass_score,
+                    report: report.clone(),
+                };
+                eval::run_eval(&backend, dataset, &request_info, &options)
+            }
+            Commands::Usage | Commands::Doctor => {
+                unreachable!("Handled before the key is needed")
+            }
+            #[cfg(feature = "mock")]
+            Commands::MockServer { .. } => unreachable!("Handled before the key is needed"),
+            Commands::Batch { command } => match command {
+                BatchCommand::Submit { input } => batch::run_submit(&backend.api, input),
+                BatchCommand::Status { id, no_follow } => {
+                    batch::run_status(&backend.api, id, !no_follow)
+                }
+                BatchCommand::Fetch { id, input, output } => {
+                    batch::run_fetch(&backend.api, id, input.as_deref(), output.as_deref())
+                }
+            },
+        };
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Prompts are recorded, as replies and as a log of the session
+    let (mut conversation_record_file, mut session_log) = match OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(REPLY_FILE)
+        .map_err(|err| format!("{REPLY_FILE}: {err}"))
+        .and_then(|file| Ok((file, open_session_log()?)))
+    {
+        Ok(records) => records,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    // One-shot mode
+    if let Some(prompt) = cmd_line_opts.prompt.as_deref() {
+        let (prompt, paths) = vision::extract_attachments(prompt);
+        let images = match paths
+            .iter()
+            .map(|path| vision::attach(path, &vision_options))
+            .collect::<Result<Vec<ImageUrl>, String>>()
+        {
+            Ok(images) => images,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        };
+        let prompt = prompt.as_str();
+        let mut verdicts = Vec::new();
+        if let Some(verdict) = backend.moderate("prompt", prompt) {
+            let blocked = verdict.action == moderation::Action::Block;
+            verdicts.push(verdict);
+            if blocked {
+                session_log.record(&SessionEvent::Blocked {
+                    prompt,
+                    answer: None,
+                    moderation: &verdicts,
+                });
+                std::process::exit(1);
+            }
+        }
+        let mut json = match backend.ask(
+            &request_info,
+            &Conversation::new(),
+            prompt,
+            &images,
+            None,
+            &mut confirm_stdin,
+        ) {
+            Ok(json) => json,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        };
+        let blocked = withhold_blocked(&backend, &mut json.choices, &mut verdicts);
+        if json.choices.is_empty() {
+            match blocked {
+                Some(answer) => session_log.record(&SessionEvent::Blocked {
+                    prompt,
+                    answer: Some(&answer),
+                    moderation: &verdicts,
+                }),
+                None => eprintln!("No answer"),
+            }
+            std::process::exit(1);
+        }
+        if blocked.is_none() {
+            backend.api.keep(&json.cache);
+        }
+        let answer = json.choices[0].text.trim_start();
+        _ = conversation_record_file
+            .write(format!("Q: {prompt}\nA: {answer}\n").as_bytes())
+            .unwrap();
+        session_log.record(&SessionEvent::Turn {
+            prompt,
+            answer,
+            model,
+            temperature,
+            alternatives: Vec::new(),
+            finish_reason: json.choices[0].finish_reason.as_deref(),
+            tool_messages: &json.tool_messages,
+            sources: &[],
+            images: images.len(),
+            moderation: &verdicts,
+            cached: json.cache.is_hit(),
+        });
+        if json.cache.is_hit() {
+            eprintln!("(cached)");
+        }
+        match cmd_line_opts.output.as_deref() {
+            Some(path) => {
+                if let Err(err) = std::fs::write(path, format!("{answer}\n")) {
+                    eprintln!("{}: {err}", path.display());
+                    std::process::exit(1);
+                }
+            }
+            None => println!("{answer}"),
+        }
+        return Ok(());
+    }
 
     // Set up readline/rustyline.  Copied from Rustyline examples
     // https://github.com/kkawakam/rustyline
@@ -239,56 +1386,261 @@ fn main() -> rustyline::Result<()> {
     // Set this to true to exit the min loop
     let mut quit: bool = false;
 
-    let mut request_info = CompletionRequestInfo::new(
-        initial_prompt.to_string(),
-        model.to_string(),
-        temperature,
-        tokens,
-    );
+    // Retrieval augmented chat
+    let mut rag: Option<rag::Rag> = match cmd_line_opts.rag.as_deref() {
+        Some(dir) => match rag::Rag::open(dir, cmd_line_opts.rag_k) {
+            Ok(rag) => Some(rag),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    // The API client. `reqwest`
-    let client = ClientBuilder::new()
-        .timeout(std::time::Duration::from_secs(120))
-        .build()
-        .unwrap();
-    let mut json: CompletionRequestInfo;
+    // The questions and answers so far.  Sent as context with each prompt
+    let mut conversation = Conversation::new();
+
+    // The prompt waiting to be sent
+    let mut pending: Option<Pending> = Some(Pending::Prompt {
+        prompt: initial_prompt.to_string(),
+        images: Vec::new(),
+    });
+
+    // Attached with `> img` for the next prompt
+    let mut attached: Vec<ImageUrl> = Vec::new();
+
+    // Set by `> retry <temperature>` for the next request only
+    let mut temperature_override: Option<f32> = None;
+
+    // The turn `> retry` took off the conversation, put back if the
+    // retry fails
+    let mut retried: Option<Turn> = None;
+
+    // Set by `> edit-last`.  Loaded into the line editor for the next input
+    let mut initial_input: Option<String> = None;
+
+    // Input from `--script` instead of the keyboard
+    let mut script = match cmd_line_opts.script.as_deref().map(script::Script::load) {
+        Some(Ok(script)) => Some(script),
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    // What `expect-contains` in a script checks.  `None` if the last
+    // request got no answer
+    let mut last_answer: Option<String> = None;
 
     let mut count = 1;
     loop {
-        _ = conversation_record_file
-            .write(format!("Q: {}\n", request_info.prompt).as_bytes())
-            .unwrap();
-        let response = match client
-            .post("https://api.openai.com/v1/completions")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {api_key}"))
-            .json(&request_info)
-            .send()
-        {
-            Ok(response) => response,
-            Err(err) => panic!("{err}"),
-        };
-        match response.status() {
-            StatusCode::OK => println!("success!"),
-            s => {
-                panic!(
-                    "Failed: Status: {}. Response.path({})",
-                    s.canonical_reason().unwrap_or("Unknown Reason"),
-                    response.url().path(),
-                );
-            }
-        };
-        json = response.json().unwrap();
-        if json.choices[0].text.is_empty() {
-            break;
+        if let Some(turn) = retried.take_if(|_| pending.is_none()) {
+            println!("The last answer is kept");
+            conversation.push_turn(turn);
         }
+        if let Some(next) = pending.take() {
+            last_answer = None;
+            // `partial` is the start of the answer when continuing, and
+            // `earlier_tools` the tool calls made for it
+            let (prompt, images, partial, earlier_tools) = match next {
+                Pending::Prompt { prompt, images } => {
+                    _ = conversation_record_file
+                        .write(format!("Q: {}\n", prompt).as_bytes())
+                        .unwrap();
+                    (prompt, images, None, Vec::new())
+                }
+                Pending::Continue(turn) => (
+                    turn.prompt,
+                    turn.images,
+                    Some(turn.answer),
+                    turn.tool_messages,
+                ),
+            };
+            // A retry asks the API again, not the cache
+            request_info.fresh = temperature_override.is_some();
+            request_info.temperature = temperature_override.take().unwrap_or(temperature);
+            request_info.n = if partial.is_some() { 1 } else { n };
+            request_info.logprobs = cmd_line_opts.logprobs;
+            request_info.sampling = sampling.clone();
 
-        _ = conversation_record_file
-            .write(format!("A: {}\n", json.choices[0].text.trim_start()).as_bytes())
-            .unwrap();
+            // Check a new prompt before it is sent
+            let mut verdicts = Vec::new();
+            if partial.is_none() {
+                if let Some(verdict) = backend.moderate("prompt", &prompt) {
+                    let blocked = verdict.action == moderation::Action::Block;
+                    verdicts.push(verdict);
+                    if blocked {
+                        session_log.record(&SessionEvent::Blocked {
+                            prompt: &prompt,
+                            answer: None,
+                            moderation: &verdicts,
+                        });
+                        continue;
+                    }
+                }
+            }
 
-        for s in json.choices[0].text.as_str().split_terminator('\n') {
-            println!("{}", justify_string(s));
+            // Add excerpts from local files to a new prompt
+            let mut sources: Vec<String> = Vec::new();
+            let mut sent = prompt.clone();
+            if let Some(rag) = rag.as_mut().filter(|r| r.enabled && partial.is_none()) {
+                match rag.retrieve(&backend.api, &prompt) {
+                    Ok(chunks) => {
+                        sources = chunks.iter().map(rag::citation).collect();
+                        sent = rag::augment(&prompt, &chunks);
+                    }
+                    Err(err) => println!("Retrieval failed: {err}"),
+                }
+            }
+            let mut json = match backend.ask(
+                &request_info,
+                &conversation,
+                &sent,
+                &images,
+                partial.as_deref(),
+                &mut |question| confirm(&mut rl, &mut script, question),
+            ) {
+                Ok(json) => json,
+                Err(err) => {
+                    println!("{err}");
+                    if let Some(partial) = partial {
+                        // Put back the turn that was being continued
+                        conversation.push_turn(Turn {
+                            tool_messages: earlier_tools,
+                            images,
+                            ..Turn::new(prompt, partial)
+                        });
+                    }
+                    continue;
+                }
+            };
+            // Every answer is checked before any is shown, and only
+            // answers that all pass are kept in the cache
+            let blocked = withhold_blocked(&backend, &mut json.choices, &mut verdicts);
+            if let Some(answer) = blocked.as_deref() {
+                if json.choices.is_empty() {
+                    println!("The answer was withheld");
+                    session_log.record(&SessionEvent::Blocked {
+                        prompt: &prompt,
+                        answer: Some(answer),
+                        moderation: &verdicts,
+                    });
+                    if let Some(partial) = partial {
+                        conversation.push_turn(Turn {
+                            tool_messages: earlier_tools,
+                            images,
+                            ..Turn::new(prompt, partial)
+                        });
+                    }
+                    continue;
+                }
+                println!("Answers that were blocked are withheld");
+            } else {
+                backend.api.keep(&json.cache);
+            }
+            let chosen = if json.choices.len() > 1 {
+                choose_answer(&mut rl, &mut script, &json.choices)
+            } else {
+                0
+            };
+            let (text, finish_reason) = match json.choices.get(chosen) {
+                Some(choice) if partial.is_some() => {
+                    (choice.text.clone(), choice.finish_reason.as_deref())
+                }
+                Some(choice) => (
+                    choice.text.trim_start().to_string(),
+                    choice.finish_reason.as_deref(),
+                ),
+                None => (String::new(), None),
+            };
+            if text.is_empty() && partial.is_none() {
+                // A script goes on, and what it expects of the answer fails
+                if script.is_none() {
+                    break;
+                }
+                println!("No answer");
+                continue;
+            }
+
+            if json.cache.is_hit() {
+                println!("\x1b[2m(cached)\x1b[0m");
+            }
+            if json.choices.len() == 1 {
+                for s in text.as_str().split_terminator('\n') {
+                    println!("{}", justify_string(s));
+                }
+            }
+            if let Some(logprobs) = json.choices.get(chosen).and_then(|c| c.logprobs.as_ref()) {
+                print!("{}", logprobs.format());
+            }
+            if !sources.is_empty() {
+                println!("\x1b[2mSources:");
+                for (i, source) in sources.iter().enumerate() {
+                    println!("  [{}] {source}", i + 1);
+                }
+                print!("\x1b[0m");
+            }
+            if finish_reason == Some("length") {
+                println!(
+                    "\x1b[1;33mThe answer ran out of tokens ({tokens}).  \
+                     `> continue` for more\x1b[0m"
+                );
+            }
+            match partial {
+                None => {
+                    _ = conversation_record_file
+                        .write(format!("A: {}\n", text).as_bytes())
+                        .unwrap();
+                    let alternatives: Vec<&str> = json
+                        .choices
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != chosen)
+                        .map(|(_, c)| c.text.trim_start())
+                        .collect();
+                    session_log.record(&SessionEvent::Turn {
+                        prompt: &prompt,
+                        answer: &text,
+                        model,
+                        temperature: request_info.temperature,
+                        alternatives,
+                        finish_reason,
+                        tool_messages: &json.tool_messages,
+                        sources: &sources,
+                        images: images.len(),
+                        moderation: &verdicts,
+                        cached: json.cache.is_hit(),
+                    });
+                    conversation.push_turn(Turn {
+                        tool_messages: json.tool_messages,
+                        images,
+                        ..Turn::new(prompt, text)
+                    });
+                    // It replaces the turn that was retried
+                    retried = None;
+                }
+                Some(partial) => {
+                    _ = conversation_record_file
+                        .write(format!("A (continued): {}\n", text).as_bytes())
+                        .unwrap();
+                    session_log.record(&SessionEvent::Continue {
+                        prompt: &prompt,
+                        text: &text,
+                        finish_reason,
+                        moderation: &verdicts,
+                    });
+                    let mut tool_messages = earlier_tools;
+                    tool_messages.extend(json.tool_messages);
+                    conversation.push_turn(Turn {
+                        tool_messages,
+                        images,
+                        ..Turn::new(prompt, partial + &text)
+                    });
+                }
+            }
+            last_answer = conversation.last().map(|t| t.answer.clone());
         }
         let mut input: String;
 
@@ -296,7 +1648,17 @@ fn main() -> rustyline::Result<()> {
         loop {
             let p = format!("{count}> ");
             rl.helper_mut().expect("No helper").colored_prompt = format!("\x1b[1;32m{p}\x1b[0m");
-            let readline = rl.readline(&p);
+            let readline = match (script.as_mut(), initial_input.take()) {
+                (Some(script), _) => match script.next_input(last_answer.as_deref()) {
+                    Some(line) => {
+                        println!("{p}{line}");
+                        Ok(line)
+                    }
+                    None => Err(ReadlineError::Eof),
+                },
+                (None, Some(initial)) => rl.readline_with_initial(&p, (initial.as_str(), "")),
+                (None, None) => rl.readline(&p),
+            };
             input = match readline {
                 Ok(line) => line,
                 Err(_) => {
@@ -319,36 +1681,297 @@ fn main() -> rustyline::Result<()> {
                             // Display the parameters
                             println!("Temperature: {temperature}");
                             println!("Model: {model}");
-                            println!("Tokens: {tokens}")
+                            println!("Tokens: {tokens}");
+                            println!("Choices: {n}");
+                            println!(
+                                "End point: {}",
+                                if backend.uses_chat(model) {
+                                    "chat"
+                                } else {
+                                    "completions"
+                                }
+                            );
+                            for parameter in sampling.describe() {
+                                println!("{parameter}");
+                            }
                         }
-                        "md" => {
-                            // Display known models
-                            let response: reqwest::blocking::Response = match client
-                                .post("https://api.openai.com/v1/models")
-                                .header("Content-Type", "application/json")
-                                .header("Authorization", format!("Bearer {api_key}"))
-                                .send()
-                            {
-                                Ok(response) => response,
-                                Err(err) => panic!("{err}"),
+                        "image" => {
+                            // Generate an image from the rest of the line
+                            let prompt = meta.collect::<Vec<&str>>().join(" ");
+                            if prompt.is_empty() {
+                                println!("Usage: > image <prompt>");
+                            } else if let Err(err) = images::run_image(
+                                &backend.api,
+                                &images::ImageOptions::default(),
+                                &prompt,
+                                &mut session_log,
+                            ) {
+                                println!("{err}");
+                            }
+                        }
+                        "img" => {
+                            // Attach an image to the next prompt
+                            let path = meta.collect::<Vec<&str>>().join(" ");
+                            if path.is_empty() {
+                                println!("Usage: > img <path>");
+                            } else {
+                                match vision::attach(Path::new(&path), &vision_options) {
+                                    Ok(image) => {
+                                        attached.push(image);
+                                        println!("{} images attached", attached.len());
+                                    }
+                                    Err(err) => println!("{err}"),
+                                }
+                            }
+                        }
+                        "detail" => {
+                            // Detail level for attached images
+                            match meta.next() {
+                                Some(detail) => match vision::check_detail(detail) {
+                                    Ok(()) => vision_options.detail = Some(detail.to_string()),
+                                    Err(err) => println!("{err}"),
+                                },
+                                None => println!(
+                                    "Detail: {}",
+                                    vision_options.detail.as_deref().unwrap_or("(default)")
+                                ),
+                            }
+                        }
+                        "cache" => match (backend.api.cache(), meta.next()) {
+                            (None, _) => println!("The cache is off.  `--cache` turns it on"),
+                            (Some(cache), Some("clear")) => {
+                                println!("Removed {} answers", cache.clear())
+                            }
+                            (Some(cache), _) => {
+                                let stats = cache.stats();
+                                println!(
+                                    "{}: {} answers, {} bytes.  This session: {} hits, {} misses",
+                                    cache.dir().display(),
+                                    stats.entries,
+                                    stats.bytes,
+                                    stats.hits,
+                                    stats.misses
+                                );
+                            }
+                        },
+                        "rag" => {
+                            // Turn retrieval on or off
+                            match (rag.as_mut(), meta.next()) {
+                                (None, _) => println!("No --rag directory"),
+                                (Some(rag), Some("on")) => rag.enabled = true,
+                                (Some(rag), Some("off")) => rag.enabled = false,
+                                (Some(rag), _) => println!(
+                                    "Retrieval from {} is {}.  > rag on|off",
+                                    rag.dir().display(),
+                                    if rag.enabled { "on" } else { "off" }
+                                ),
+                            }
+                        }
+                        "tools" => {
+                            // List the tools the model can use
+                            if backend.tools.is_empty() && backend.workspace.is_none() {
+                                println!("No tools configured");
+                            }
+                            if let Some(workspace) = backend.workspace.as_ref() {
+                                println!(
+                                    "Built in: read_file, list_dir, grep, write_file, \
+                                     run_command in {}",
+                                    workspace.root().display()
+                                );
+                            }
+                            for tool in backend.tools.iter() {
+                                println!(
+                                    "{}{}: {}\n    {}",
+                                    tool.name,
+                                    if tool.auto_approve { " (auto)" } else { "" },
+                                    tool.description,
+                                    tool.command.join(" ")
+                                );
+                            }
+                        }
+                        "set" => {
+                            // Set a sampling parameter.  The value is
+                            // the rest of the line
+                            let key = meta.next().unwrap_or("");
+                            let value = meta.collect::<Vec<&str>>().join(" ");
+                            let set = if key == "response_format" && !backend.uses_chat(model) {
+                                Err(no_response_format(model))
+                            } else {
+                                sampling.set(key, &value)
                             };
-                            match response.status() {
-                                StatusCode::OK => println!("success!"),
-                                s => {
-                                    panic!(
-                                        "Failed: Status: {}. Response.path({})",
-                                        s.canonical_reason().unwrap_or("Unknown Reason"),
-                                        response.url().path(),
-                                    );
+                            if let Err(err) = set {
+                                println!("{err}");
+                                println!("Parameters: {}", sampling::KEYS.join(", "));
+                            }
+                            drop_schema(&mut backend, &sampling);
+                        }
+                        "unset" => {
+                            let key = meta.next().unwrap_or("");
+                            if let Err(err) = sampling.unset(key) {
+                                println!("{err}");
+                                println!("Parameters: {}", sampling::KEYS.join(", "));
+                            }
+                            drop_schema(&mut backend, &sampling);
+                        }
+                        "n" => {
+                            // Set the number of answers to ask for
+                            match meta.next().map(|v| v.parse::<u32>()) {
+                                Some(Ok(v)) if v > 0 => n = v,
+                                _ => println!("Usage: > n <number greater than zero>"),
+                            }
+                        }
+                        "retry" => {
+                            // Send the last prompt again, optionally
+                            // with a different temperature
+                            let retry_temperature = match meta.next().map(|t| t.parse::<f32>()) {
+                                Some(Ok(t)) => t,
+                                Some(Err(err)) => {
+                                    println!("Bad temperature: {err}");
+                                    continue;
                                 }
+                                None => temperature,
                             };
-                            println!("{:?}", &response);
-                            json = response.json().unwrap();
-
-                            // let model_json: String = response.json().unwrap();
-                            // println!("{model_json}");
-                            // curl https://api.openai.com/v1/models \
-                            //   -H 'Authorization: Bearer YOUR_API_KEY'
+                            match conversation.pop() {
+                                Some(turn) => {
+                                    session_log.record(&SessionEvent::Retry {
+                                        prompt: &turn.prompt,
+                                        temperature: retry_temperature,
+                                    });
+                                    temperature_override = Some(retry_temperature);
+                                    pending = Some(Pending::Prompt {
+                                        prompt: turn.prompt.clone(),
+                                        images: turn.images.clone(),
+                                    });
+                                    retried = Some(turn);
+                                    break;
+                                }
+                                None => println!("Nothing to retry"),
+                            }
+                        }
+                        "key" => {
+                            // Switch profile, or list them
+                            match meta.next() {
+                                None => {
+                                    let current = &backend.api.credentials().profile;
+                                    for name in std::iter::once(profiles::DEFAULT)
+                                        .chain(configuration.profiles.keys().map(String::as_str))
+                                    {
+                                        let mark = if name == current { "*" } else { " " };
+                                        println!("{mark} {name}");
+                                    }
+                                }
+                                Some(name) => match switch_credentials(
+                                    name,
+                                    &startup_credentials,
+                                    &cmd_line_opts,
+                                    &configuration.profiles,
+                                    &configuration.api_key,
+                                ) {
+                                    Ok(credentials) => {
+                                        println!("Using {}", credentials.describe());
+                                        session_log.record(&SessionEvent::Key { profile: name });
+                                        backend.api.set_credentials(credentials);
+                                    }
+                                    Err(err) => println!("{err}"),
+                                },
+                            }
+                        }
+                        "usage" => match backend.api.ledger() {
+                            Some(ledger) => print!("{}", ledger.session_table()),
+                            None => println!("Usage is not being recorded"),
+                        },
+                        "compare" => {
+                            // Ask other models the last prompt, and
+                            // maybe continue with one of their answers
+                            let models: Vec<String> = meta.map(str::to_string).collect();
+                            if models.is_empty() {
+                                println!("Usage: > compare <model> <model>...");
+                                continue;
+                            }
+                            let Some(turn) = conversation.pop() else {
+                                println!("Nothing to compare");
+                                continue;
+                            };
+                            request_info.temperature = temperature;
+                            request_info.sampling = sampling.clone();
+                            request_info.logprobs = None;
+                            let outcomes = compare::ask_all(
+                                &backend,
+                                &request_info,
+                                &models,
+                                &conversation,
+                                &turn.prompt,
+                                &turn.images,
+                            );
+                            print!("{}", compare::layout(&outcomes, compare::terminal_width()));
+                            let chosen = choose_compared(&mut rl, &mut script, &outcomes);
+                            session_log.record(&SessionEvent::Compare {
+                                prompt: &turn.prompt,
+                                results: outcomes.iter().map(compare::Outcome::summary).collect(),
+                                chosen: chosen.map(|o| o.model.as_str()),
+                            });
+                            let answer = chosen.and_then(|outcome| {
+                                Some((outcome.answer()?, outcome.answers.as_ref().ok()?))
+                            });
+                            match answer {
+                                Some((answer, answers)) => conversation.push_turn(Turn {
+                                    tool_messages: answers.tool_messages.clone(),
+                                    images: turn.images,
+                                    ..Turn::new(turn.prompt, answer.to_string())
+                                }),
+                                None => conversation.push_turn(turn),
+                            }
+                        }
+                        "continue" => {
+                            // Ask for more of the last answer
+                            match conversation.pop() {
+                                Some(turn) => {
+                                    pending = Some(Pending::Continue(turn));
+                                    break;
+                                }
+                                None => println!("Nothing to continue"),
+                            }
+                        }
+                        "undo" => {
+                            // Drop the last question and answer from the context
+                            match conversation.pop() {
+                                Some(turn) => {
+                                    session_log.record(&SessionEvent::Undo {
+                                        prompt: &turn.prompt,
+                                        answer: &turn.answer,
+                                    });
+                                    println!("Removed: {}", turn.prompt);
+                                }
+                                None => println!("Nothing to undo"),
+                            }
+                        }
+                        "edit-last" => {
+                            // Drop the last turn and put its prompt in the
+                            // editor to be changed and sent again
+                            match conversation.pop() {
+                                Some(turn) => {
+                                    session_log.record(&SessionEvent::EditLast {
+                                        prompt: &turn.prompt,
+                                    });
+                                    initial_input = Some(turn.prompt);
+                                }
+                                None => println!("Nothing to edit"),
+                            }
+                        }
+                        "md" => {
+                            // Display known models
+                            match backend.api.get_json::<ModelRequestInfo>("/models") {
+                                Ok(models) => {
+                                    for model in models.data.iter() {
+                                        if finetune::is_fine_tuned(&model.id) {
+                                            println!("\x1b[1;32m{}\x1b[0m (fine-tuned)", model.id);
+                                        } else {
+                                            println!("{}", model.id);
+                                        }
+                                    }
+                                }
+                                Err(err) => println!("{err}"),
+                            }
                         }
 
                         _ => (),
@@ -361,13 +1984,36 @@ fn main() -> rustyline::Result<()> {
         if quit {
             break;
         }
-        rl.add_history_entry(input.as_str())?;
+        if pending.is_some() {
+            // A meta command has set up the next prompt
+            continue;
+        }
+        // What a script types is not the user's history
+        if script.is_none() {
+            rl.add_history_entry(input.as_str())?;
+        }
         println!("You entered: {}", input);
-        request_info.prompt = input;
+        let (prompt, paths) = vision::extract_attachments(&input);
+        match paths
+            .iter()
+            .map(|path| vision::attach(path, &vision_options))
+            .collect::<Result<Vec<ImageUrl>, String>>()
+        {
+            Ok(mut images) => {
+                images.splice(0..0, attached.drain(..));
+                pending = Some(Pending::Prompt { prompt, images });
+            }
+            Err(err) => println!("{err}"),
+        }
     }
-    if !json.choices.is_empty() {
-        request_info.prompt = json.choices[0].text.clone();
+    if script.is_none() {
+        rl.append_history("history.txt")?;
+    }
+    if let Some(script) = script {
+        println!("{}", script.summary());
+        if script.failed > 0 {
+            std::process::exit(1);
+        }
     }
-    rl.append_history("history.txt")
-    // Ok(())
+    Ok(())
 }
@@ -0,0 +1,355 @@
+//! A stand in for the OpenAI API, for testing without a network.  It
+//! answers `/v1/completions`, `/v1/chat/completions` (streamed too),
+//! `/v1/models` and anything else from a list of fixtures.  Run it with
+//! the `mock-server` subcommand, built with the `mock` feature, and
+//! point the programme at it with `--base-url`, or start it from a test
+//! with `MockServer::start`.  It answers requests sent to it as a proxy
+//! too.
+//!
+//! A fixture file is a JSON array.  The first fixture that matches a
+//! request answers it.  For example:
+//!
+//! ```json
+//! [
+//!     {"path": "/v1/chat/completions", "contains": "weather",
+//!      "status": 429, "body": {"error": {"message": "Slow down"}}, "times": 1},
+//!     {"path": "/v1/chat/completions", "answer": "Sunny"}
+//! ]
+//! ```
+use crate::model_example_data::ModelExampleData;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// What the mock says when no fixture has an answer
+pub const DEFAULT_ANSWER: &str = "This is a mock answer.";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fixture {
+    /// Any method if not set
+    #[serde(default)]
+    pub method: Option<String>,
+    pub path: String,
+    /// Only requests whose body contains this
+    #[serde(default)]
+    pub contains: Option<String>,
+    #[serde(default = "ok")]
+    pub status: u16,
+    /// The response body, as it is
+    #[serde(default)]
+    pub body: Option<Value>,
+    /// Or the answer, in a response shaped for the end point
+    #[serde(default)]
+    pub answer: Option<String>,
+    /// Answer this many requests, then stop matching.  Forever if not set
+    #[serde(default)]
+    pub times: Option<usize>,
+}
+
+fn ok() -> u16 {
+    200
+}
+
+impl Fixture {
+    fn matches(&self, request: &Request) -> bool {
+        self.times != Some(0)
+            && self.path == request.path
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|m| m.eq_ignore_ascii_case(&request.method))
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|c| request.body.contains(c.as_str()))
+    }
+}
+
+/// A request the mock received
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Without the query
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+/// A running mock.  It stops when the programme does
+pub struct MockServer {
+    /// e.g. "http://127.0.0.1:41234/v1", for `--base-url`
+    pub url: String,
+    /// Every request received, in order
+    pub requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Listen on `port` (0 for any free port) on localhost, in a thread
+    pub fn start(port: u16, fixtures: Vec<Fixture>) -> Result<Self, String> {
+        let listener =
+            TcpListener::bind(("127.0.0.1", port)).map_err(|err| format!("Mock server: {err}"))?;
+        let address = listener
+            .local_addr()
+            .map_err(|err| format!("Mock server: {err}"))?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let fixtures = Arc::new(Mutex::new(fixtures));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let fixtures = fixtures.clone();
+                let received = received.clone();
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, &fixtures, &received) {
+                        eprintln!("Mock server: {err}");
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            url: format!("http://{address}/v1"),
+            requests,
+        })
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Read fixtures from a file
+pub fn load_fixtures(path: &Path) -> Result<Vec<Fixture>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    serde_json::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+/// Answer one connection.  Connections are closed after each response
+fn serve(
+    stream: TcpStream,
+    fixtures: &Mutex<Vec<Fixture>>,
+    received: &Mutex<Vec<Request>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let mut target = parts.next().unwrap_or_default();
+    // Sent to it as a proxy, the target is the whole URL
+    if let Some(rest) = target.strip_prefix("http://") {
+        target = rest.find('/').map(|i| &rest[i..]).unwrap_or("/");
+    }
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let length = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let request = Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    };
+    received.lock().unwrap().push(request.clone());
+    let (status, content_type, body) = respond(&request, fixtures);
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+/// The status, content type and body for `request`
+fn respond(request: &Request, fixtures: &Mutex<Vec<Fixture>>) -> (u16, &'static str, String) {
+    let fixture = {
+        let mut fixtures = fixtures.lock().unwrap();
+        fixtures
+            .iter_mut()
+            .find(|f| f.matches(request))
+            .map(|fixture| {
+                if let Some(times) = fixture.times.as_mut() {
+                    *times -= 1;
+                }
+                fixture.clone()
+            })
+    };
+    let (status, answer) = match fixture {
+        Some(Fixture {
+            status,
+            body: Some(body),
+            ..
+        }) => return (status, "application/json", body.to_string()),
+        Some(fixture) => (fixture.status, fixture.answer),
+        None => (200, None),
+    };
+    let answer = answer.unwrap_or_else(|| DEFAULT_ANSWER.to_string());
+    let json = request.json();
+    let stream = json["stream"] == true;
+    let body = match request.path.as_str() {
+        "/v1/completions" if stream => return sse(completion_chunks(&json, &answer)),
+        "/v1/completions" => completion(&json, &answer),
+        "/v1/chat/completions" if stream => return sse(chat_chunks(&json, &answer)),
+        "/v1/chat/completions" => chat_completion(&json, &answer),
+        "/v1/models" => serde_json::from_str(&ModelExampleData::new().json).unwrap(),
+        _ => {
+            return (
+                404,
+                "application/json",
+                json!({"error": {"message": format!("No fixture for {}", request.path)}})
+                    .to_string(),
+            )
+        }
+    };
+    (status, "application/json", body.to_string())
+}
+
+/// How many choices were asked for
+fn choices(request: &Value) -> u64 {
+    request["n"].as_u64().unwrap_or(1)
+}
+
+fn usage(answer: &str) -> Value {
+    let tokens = answer.split_whitespace().count();
+    json!({"prompt_tokens": 10, "completion_tokens": tokens, "total_tokens": 10 + tokens})
+}
+
+fn completion(request: &Value, answer: &str) -> Value {
+    let choices: Vec<Value> = (0..choices(request))
+        .map(|i| json!({"text": answer, "index": i, "logprobs": null, "finish_reason": "stop"}))
+        .collect();
+    json!({
+        "id": "cmpl-mock",
+        "object": "text_completion",
+        "created": 0,
+        "model": request["model"],
+        "choices": choices,
+        "usage": usage(answer),
+    })
+}
+
+fn chat_completion(request: &Value, answer: &str) -> Value {
+    let choices: Vec<Value> = (0..choices(request))
+        .map(|i| {
+            json!({
+                "index": i,
+                "message": {"role": "assistant", "content": answer},
+                "logprobs": null,
+                "finish_reason": "stop",
+            })
+        })
+        .collect();
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion",
+        "created": 0,
+        "model": request["model"],
+        "choices": choices,
+        "usage": usage(answer),
+    })
+}
+
+/// `answer` a word at a time, keeping the spaces
+fn pieces(answer: &str) -> Vec<String> {
+    answer.split_inclusive(' ').map(str::to_string).collect()
+}
+
+fn completion_chunks(request: &Value, answer: &str) -> Vec<Value> {
+    let mut chunks: Vec<Value> = pieces(answer)
+        .into_iter()
+        .map(|text| {
+            json!({"id": "cmpl-mock", "object": "text_completion", "model": request["model"],
+                   "choices": [{"text": text, "index": 0, "finish_reason": null}]})
+        })
+        .collect();
+    chunks.push(
+        json!({"id": "cmpl-mock", "object": "text_completion", "model": request["model"],
+                       "choices": [{"text": "", "index": 0, "finish_reason": "stop"}]}),
+    );
+    chunks
+}
+
+fn chat_chunks(request: &Value, answer: &str) -> Vec<Value> {
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({"id": "chatcmpl-mock", "object": "chat.completion.chunk", "model": request["model"],
+               "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]})
+    };
+    let mut chunks = vec![chunk(
+        json!({"role": "assistant", "content": ""}),
+        Value::Null,
+    )];
+    chunks.extend(
+        pieces(answer)
+            .into_iter()
+            .map(|text| chunk(json!({"content": text}), Value::Null)),
+    );
+    chunks.push(chunk(json!({}), json!("stop")));
+    chunks
+}
+
+/// Server sent events, ending with `[DONE]`
+fn sse(chunks: Vec<Value>) -> (u16, &'static str, String) {
+    let mut body = String::new();
+    for chunk in chunks {
+        body.push_str(&format!("data: {chunk}\n\n"));
+    }
+    body.push_str("data: [DONE]\n\n");
+    (200, "text/event-stream", body)
+}
+
+/// For the `mock-server` subcommand.  Runs until it is killed
+pub fn run_mock_server(port: u16, fixtures: Option<&Path>) -> Result<(), String> {
+    let fixtures = match fixtures {
+        Some(path) => load_fixtures(path)?,
+        None => Vec::new(),
+    };
+    let server = MockServer::start(port, fixtures)?;
+    println!("{}", server.url);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,207 @@
+//! Checking prompts, and optionally answers, with the moderation end
+//! point before they are used.  Each category can have a threshold to
+//! warn at and one to block at.  See
+//! https://platform.openai.com/docs/api-reference/moderations
+use crate::api_client::ApiClient;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const DEFAULT_MODEL: &str = "omni-moderation-latest";
+
+/// The `moderation` section of the configuration.  For example:
+///
+/// ```json
+/// {
+///     "answers": true,
+///     "block": {"violence": 0.7, "self-harm": 0.3},
+///     "warn": {"harassment": 0.2}
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub model: String,
+    /// Check answers as well as prompts
+    pub answers: bool,
+    /// Block when a category's score is at least this
+    pub block: BTreeMap<String, f64>,
+    /// Warn when a category's score is at least this
+    pub warn: BTreeMap<String, f64>,
+    /// What to do about categories the API flags that have no threshold
+    pub flagged: Action,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            model: DEFAULT_MODEL.to_string(),
+            answers: false,
+            block: BTreeMap::new(),
+            warn: BTreeMap::new(),
+            flagged: Action::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Pass,
+    Warn,
+    Block,
+}
+
+#[derive(Debug, Serialize)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    #[serde(default)]
+    categories: BTreeMap<String, bool>,
+    #[serde(default)]
+    category_scores: BTreeMap<String, f64>,
+}
+
+/// A category that led to a warning or a block
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryScore {
+    pub category: String,
+    pub score: f64,
+    pub action: Action,
+}
+
+/// The outcome of checking some text.  Recorded in the session log
+#[derive(Debug, Clone, Serialize)]
+pub struct Verdict {
+    /// "prompt" or "answer"
+    pub input: &'static str,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<CategoryScore>,
+}
+
+impl Verdict {
+    /// The flagged categories, with their scores, for display
+    pub fn describe(&self) -> String {
+        let mut result = format!(
+            "Moderation of the {}: {}\n",
+            self.input,
+            match self.action {
+                Action::Pass => "passed",
+                Action::Warn => "warning",
+                Action::Block => "blocked",
+            }
+        );
+        for category in self.categories.iter() {
+            result.push_str(&format!(
+                "  {:<24} {:.3} {}\n",
+                category.category,
+                category.score,
+                if category.action == Action::Block {
+                    "block"
+                } else {
+                    "warn"
+                }
+            ));
+        }
+        result
+    }
+}
+
+/// Check `text` with the moderation end point.  `input` says what it
+/// is, "prompt" or "answer"
+pub fn check(
+    api: &ApiClient,
+    config: &ModerationConfig,
+    input: &'static str,
+    text: &str,
+) -> Result<Verdict, String> {
+    let response: ModerationResponse = api
+        .post_json(
+            "/moderations",
+            &ModerationRequest {
+                model: &config.model,
+                input: text,
+            },
+        )
+        .map_err(|e| format!("Moderation failed: {e}"))?;
+    let result = response
+        .results
+        .first()
+        .ok_or_else(|| "Moderation failed: no result".to_string())?;
+    Ok(judge(config, input, result))
+}
+
+/// Apply the thresholds in `config` to `result`
+fn judge(config: &ModerationConfig, input: &'static str, result: &ModerationResult) -> Verdict {
+    let mut categories = Vec::new();
+    for (category, score) in result.category_scores.iter() {
+        let over = |thresholds: &BTreeMap<String, f64>| {
+            thresholds.get(category).map(|threshold| score >= threshold)
+        };
+        let action = match (over(&config.block), over(&config.warn)) {
+            (Some(true), _) => Action::Block,
+            (_, Some(true)) => Action::Warn,
+            (None, None) if result.categories.get(category) == Some(&true) => config.flagged,
+            _ => Action::Pass,
+        };
+        if action != Action::Pass {
+            categories.push(CategoryScore {
+                category: category.clone(),
+                score: *score,
+                action,
+            });
+        }
+    }
+    Verdict {
+        input,
+        action: categories
+            .iter()
+            .map(|c| c.action)
+            .max()
+            .unwrap_or(Action::Pass),
+        categories,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn thresholds() {
+        let config: ModerationConfig = serde_json::from_str(
+            r#"{"block": {"violence": 0.7}, "warn": {"violence": 0.2, "harassment": 0.5}}"#,
+        )
+        .unwrap();
+        let result: ModerationResult = serde_json::from_str(
+            r#"{"categories": {"violence": false, "harassment": false, "hate": true},
+                "category_scores": {"violence": 0.3, "harassment": 0.1, "hate": 0.9}}"#,
+        )
+        .unwrap();
+        let verdict = judge(&config, "prompt", &result);
+        assert_eq!(verdict.action, Action::Block);
+        let actions: Vec<(&str, Action)> = verdict
+            .categories
+            .iter()
+            .map(|c| (c.category.as_str(), c.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [("hate", Action::Block), ("violence", Action::Warn)]
+        );
+
+        let config = ModerationConfig {
+            flagged: Action::Warn,
+            ..config
+        };
+        assert_eq!(judge(&config, "prompt", &result).action, Action::Warn);
+    }
+}
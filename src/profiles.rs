@@ -0,0 +1,139 @@
+//! Profiles: an API key with the organization and project requests made
+//! with it are for.  They are in the `profiles` section of the
+//! configuration, chosen with `--profile` and switched with `> key`:
+//!
+//! ```json
+//! {
+//!     "profiles": {
+//!         "work": {"command": "pass show openai/work", "organization": "org-abc",
+//!                  "project": "proj_123"},
+//!         "personal": {"env": "OPENAI_PERSONAL_KEY"}
+//!     }
+//! }
+//! ```
+//!
+//! A profile finds its key as `api_key` does, from its own `command`,
+//! `file` or `env`, and only there.  No profile may be called `default`:
+//! that is the key used without `--profile`
+use crate::api_key::{self, ApiKey, KeyConfig};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+
+/// The name of the credentials used without `--profile`
+pub const DEFAULT: &str = "default";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub key: KeyConfig,
+    /// Sent as the OpenAI-Organization header
+    #[serde(default)]
+    pub organization: Option<String>,
+    /// Sent as the OpenAI-Project header
+    #[serde(default)]
+    pub project: Option<String>,
+}
+
+/// Who requests are made as
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    /// The profile's name
+    pub profile: String,
+    pub key: ApiKey,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+}
+
+impl Credentials {
+    pub fn new(key: ApiKey) -> Self {
+        Self {
+            profile: DEFAULT.to_string(),
+            key,
+            organization: None,
+            project: None,
+        }
+    }
+
+    /// The default credentials, with the organization and project in
+    /// $OPENAI_ORG_ID and $OPENAI_PROJECT_ID, as the OpenAI libraries
+    /// use
+    pub fn from_env(key: ApiKey) -> Self {
+        Self {
+            organization: env::var("OPENAI_ORG_ID").ok(),
+            project: env::var("OPENAI_PROJECT_ID").ok(),
+            ..Self::new(key)
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut scope = Vec::new();
+        if let Some(organization) = self.organization.as_deref() {
+            scope.push(format!("organization {organization}"));
+        }
+        if let Some(project) = self.project.as_deref() {
+            scope.push(format!("project {project}"));
+        }
+        if scope.is_empty() {
+            self.profile.clone()
+        } else {
+            format!("{} ({})", self.profile, scope.join(", "))
+        }
+    }
+}
+
+/// The credentials of the profile `name`
+pub fn load(profiles: &BTreeMap<String, Profile>, name: &str) -> Result<Credentials, String> {
+    let profile = profiles.get(name).ok_or_else(|| {
+        let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        if names.is_empty() {
+            format!("No profile {name}: there are none in the configuration")
+        } else {
+            format!("No profile {name}: there are {}", names.join(", "))
+        }
+    })?;
+    let key = api_key::from_config(&profile.key).map_err(|err| format!("Profile {name}: {err}"))?;
+    Ok(Credentials {
+        profile: name.to_string(),
+        key,
+        organization: profile.organization.clone(),
+        project: profile.project.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn profiles() {
+        let profiles: BTreeMap<String, Profile> = serde_json::from_str(
+            r#"{"work": {"command": "echo sk-work", "organization": "org-1", "project": "proj_1"}}"#,
+        )
+        .unwrap();
+        let credentials = load(&profiles, "work").unwrap();
+        assert_eq!(credentials.key.expose(), "sk-work");
+        assert_eq!(
+            credentials.describe(),
+            "work (organization org-1, project proj_1)"
+        );
+        assert!(load(&profiles, "home")
+            .unwrap_err()
+            .contains("there are work"));
+    }
+
+    #[test]
+    fn profiles_need_their_own_key() {
+        let profiles: BTreeMap<String, Profile> = serde_json::from_str(
+            r#"{"keyless": {"project": "proj_1"},
+                "unset": {"env": "OPEN_AI_CHAT_GPT3_UNSET_TEST_KEY"}}"#,
+        )
+        .unwrap();
+        let err = load(&profiles, "keyless").unwrap_err();
+        assert!(err.starts_with("Profile keyless: No API key"), "{err}");
+        let err = load(&profiles, "unset").unwrap_err();
+        assert_eq!(
+            err,
+            "Profile unset: $OPEN_AI_CHAT_GPT3_UNSET_TEST_KEY is not set"
+        );
+    }
+}
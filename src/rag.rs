@@ -0,0 +1,145 @@
+//! Retrieval augmented chat.  The text files in a folder are embedded
+//! into an index kept in the folder (`.open_ai_index.json`).  Before
+//! each prompt is sent the index is brought up to date, by re-embedding
+//! files whose content hash has changed, and the chunks most like the
+//! prompt are added to it, numbered, with where they came from.
+use crate::api_client::ApiClient;
+use crate::embeddings::{self, Chunk, VectorIndex};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = ".open_ai_index.json";
+
+/// Lines in each chunk
+const CHUNK_LINES: usize = 40;
+
+pub struct Rag {
+    dir: PathBuf,
+    index: VectorIndex,
+    /// How many chunks to add to each prompt
+    pub k: usize,
+    /// Set by `> rag on|off`
+    pub enabled: bool,
+}
+
+impl Rag {
+    pub fn open(dir: &Path, k: usize) -> Result<Self, String> {
+        if !dir.is_dir() {
+            return Err(format!("{} is not a directory", dir.display()));
+        }
+        let mut index = VectorIndex::load(&dir.join(INDEX_FILE))?;
+        if index.model.is_empty() {
+            index.model = embeddings::DEFAULT_MODEL.to_string();
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index,
+            k,
+            enabled: true,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Re-embed files that are new or have changed, and forget files
+    /// that have gone.  Returns how many files were embedded
+    pub fn update(&mut self, api: &ApiClient) -> Result<usize, String> {
+        let known: HashMap<String, String> = self
+            .index
+            .chunks
+            .iter()
+            .map(|c| (c.source.clone(), c.hash.clone()))
+            .collect();
+        let files = embeddings::text_files(&self.dir);
+        let sources: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+        let before = self.index.chunks.len();
+        self.index.chunks.retain(|c| sources.contains(&c.source));
+        let mut changed = before != self.index.chunks.len();
+        let mut embedded = 0;
+        for (file, source) in files.iter().zip(sources.iter()) {
+            let text = match fs::read_to_string(file) {
+                Ok(text) => text,
+                Err(_) => continue,
+            };
+            if known.get(source) == Some(&embeddings::sha256(text.as_bytes())) {
+                continue;
+            }
+            let chunks = embeddings::embed_text(api, &self.index.model, source, &text, CHUNK_LINES)
+                .map_err(|e| e.to_string())?;
+            self.index.replace(source, chunks);
+            embedded += 1;
+            changed = true;
+        }
+        if changed {
+            self.index.save(&self.dir.join(INDEX_FILE))?;
+        }
+        Ok(embedded)
+    }
+
+    /// The chunks most like `query`, after bringing the index up to date
+    pub fn retrieve(&mut self, api: &ApiClient, query: &str) -> Result<Vec<Chunk>, String> {
+        let embedded = self.update(api)?;
+        if embedded > 0 {
+            println!("Indexed {embedded} changed files in {}", self.dir.display());
+        }
+        let vector = embeddings::embed(api, &self.index.model, &[query.to_string()])
+            .map_err(|e| e.to_string())?
+            .remove(0);
+        Ok(self
+            .index
+            .search(&vector, self.k)
+            .into_iter()
+            .map(|(_, c)| c.clone())
+            .collect())
+    }
+}
+
+/// "file:first-last"
+pub fn citation(chunk: &Chunk) -> String {
+    format!("{}:{}-{}", chunk.source, chunk.start_line, chunk.end_line)
+}
+
+/// `prompt` with the `chunks` before it, numbered so the answer can
+/// cite them
+pub fn augment(prompt: &str, chunks: &[Chunk]) -> String {
+    if chunks.is_empty() {
+        return prompt.to_string();
+    }
+    let mut result = String::from(
+        "Use these excerpts from local files if they are relevant.  \
+         Cite the ones you use by their number, like [1].\n\n",
+    );
+    for (i, chunk) in chunks.iter().enumerate() {
+        result.push_str(&format!(
+            "[{}] {}\n```\n{}```\n\n",
+            i + 1,
+            citation(chunk),
+            chunk.text
+        ));
+    }
+    result.push_str(&format!("Question: {prompt}"));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn augment_cites_sources() {
+        let chunk = Chunk {
+            source: "docs/a.md".to_string(),
+            start_line: 3,
+            end_line: 9,
+            text: "Keys rotate monthly.\n".to_string(),
+            vector: Vec::new(),
+            hash: String::new(),
+        };
+        assert_eq!(augment("Why?", &[]), "Why?");
+        let augmented = augment("When do keys rotate?", &[chunk]);
+        assert!(augmented.contains("[1] docs/a.md:3-9\n```\nKeys rotate monthly.\n```"));
+        assert!(augmented.ends_with("Question: When do keys rotate?"));
+    }
+}
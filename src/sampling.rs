@@ -0,0 +1,239 @@
+//! Parameters that control how the model samples its answer, beyond
+//! `temperature` and `max_tokens`.  They can come from the
+//! configuration file, the command line, or `> set` in the REPL.  Only
+//! those that are set are sent.  See
+//! https://platform.openai.com/docs/api-reference/completions/create
+use crate::json_schema::JsonSchemaFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    /// Set with `--json-schema`
+    JsonSchema {
+        json_schema: JsonSchemaFormat,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Up to four sequences where the model will stop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Token id to bias, from -100 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<String, i32>>,
+    /// Identifies the end user to OpenAI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// The names accepted by `Sampling::set`
+pub const KEYS: [&str; 8] = [
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "stop",
+    "seed",
+    "logit_bias",
+    "user",
+    "response_format",
+];
+
+impl Sampling {
+    /// Overwrite the parameters here with those set in `other`
+    pub fn merge(&mut self, other: Sampling) {
+        if other.top_p.is_some() {
+            self.top_p = other.top_p;
+        }
+        if other.presence_penalty.is_some() {
+            self.presence_penalty = other.presence_penalty;
+        }
+        if other.frequency_penalty.is_some() {
+            self.frequency_penalty = other.frequency_penalty;
+        }
+        if other.stop.is_some() {
+            self.stop = other.stop;
+        }
+        if other.seed.is_some() {
+            self.seed = other.seed;
+        }
+        if other.logit_bias.is_some() {
+            self.logit_bias = other.logit_bias;
+        }
+        if other.user.is_some() {
+            self.user = other.user;
+        }
+        if other.response_format.is_some() {
+            self.response_format = other.response_format;
+        }
+    }
+
+    /// Check the parameters are in the ranges the API accepts
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(format!("top_p must be from 0 to 1, not {top_p}"));
+            }
+        }
+        for (name, penalty) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if let Some(penalty) = penalty {
+                if !(-2.0..=2.0).contains(&penalty) {
+                    return Err(format!("{name} must be from -2 to 2, not {penalty}"));
+                }
+            }
+        }
+        if let Some(stop) = self.stop.as_ref() {
+            if stop.len() > 4 {
+                return Err(format!("At most 4 stop sequences, not {}", stop.len()));
+            }
+            if stop.iter().any(|s| s.is_empty()) {
+                return Err("Stop sequences cannot be empty".to_string());
+            }
+        }
+        if let Some(logit_bias) = self.logit_bias.as_ref() {
+            for (token, bias) in logit_bias.iter() {
+                if token.parse::<u32>().is_err() {
+                    return Err(format!("logit_bias: {token:?} is not a token id"));
+                }
+                if !(-100..=100).contains(bias) {
+                    return Err(format!(
+                        "logit_bias: {bias} for {token} must be from -100 to 100"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the parameter named `key` from the text `value`.  `stop` is
+    /// one sequence, or a JSON array of them.  `logit_bias` is a JSON
+    /// object, or `TOKEN:BIAS` pairs separated by commas.  The result is
+    /// validated, and on error nothing is changed
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let mut updated = self.clone();
+        match key {
+            "top_p" => updated.top_p = Some(parse(key, value)?),
+            "presence_penalty" => updated.presence_penalty = Some(parse(key, value)?),
+            "frequency_penalty" => updated.frequency_penalty = Some(parse(key, value)?),
+            "seed" => updated.seed = Some(parse(key, value)?),
+            "user" => updated.user = Some(value.to_string()),
+            "stop" => updated.stop = Some(parse_stop(value)?),
+            "logit_bias" => updated.logit_bias = Some(parse_logit_bias(value)?),
+            "response_format" => {
+                updated.response_format = Some(match value {
+                    "text" => ResponseFormat::Text,
+                    "json_object" => ResponseFormat::JsonObject,
+                    _ => return Err(format!("response_format: {value}: text or json_object")),
+                })
+            }
+            _ => return Err(format!("Unknown parameter: {key}")),
+        }
+        updated.validate()?;
+        *self = updated;
+        Ok(())
+    }
+
+    /// Go back to the API's default for `key`
+    pub fn unset(&mut self, key: &str) -> Result<(), String> {
+        match key {
+            "top_p" => self.top_p = None,
+            "presence_penalty" => self.presence_penalty = None,
+            "frequency_penalty" => self.frequency_penalty = None,
+            "seed" => self.seed = None,
+            "user" => self.user = None,
+            "stop" => self.stop = None,
+            "logit_bias" => self.logit_bias = None,
+            "response_format" => self.response_format = None,
+            _ => return Err(format!("Unknown parameter: {key}")),
+        }
+        Ok(())
+    }
+
+    /// "name: value" for each parameter that is set
+    pub fn describe(&self) -> Vec<String> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(map)) => {
+                map.iter().map(|(k, v)| format!("{k}: {v}")).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|err| format!("{key}: {value}: {err}"))
+}
+
+/// One stop sequence, with "\n" and "\t" unescaped so they can be
+/// typed, or a JSON array of them
+pub fn parse_stop(value: &str) -> Result<Vec<String>, String> {
+    if value.starts_with('[') {
+        serde_json::from_str(value).map_err(|err| format!("stop: {err}"))
+    } else {
+        Ok(vec![value.replace("\\n", "\n").replace("\\t", "\t")])
+    }
+}
+
+pub fn parse_logit_bias(value: &str) -> Result<BTreeMap<String, i32>, String> {
+    if value.starts_with('{') {
+        return serde_json::from_str(value).map_err(|err| format!("logit_bias: {err}"));
+    }
+    let mut result = BTreeMap::new();
+    for pair in value.split(',') {
+        let (token, bias) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("logit_bias: {pair}: expected TOKEN:BIAS"))?;
+        result.insert(token.trim().to_string(), parse("logit_bias", bias.trim())?);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn only_set_parameters_are_serialised() {
+        let mut sampling = Sampling::default();
+        assert_eq!(serde_json::to_string(&sampling).unwrap(), "{}");
+        sampling.set("top_p", "0.5").unwrap();
+        sampling.set("stop", "\\n").unwrap();
+        sampling.set("logit_bias", "50256:-100").unwrap();
+        assert_eq!(
+            serde_json::to_string(&sampling).unwrap(),
+            r#"{"top_p":0.5,"stop":["\n"],"logit_bias":{"50256":-100}}"#
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let mut sampling = Sampling::default();
+        assert!(sampling.set("top_p", "1.5").is_err());
+        assert!(sampling.set("presence_penalty", "-3").is_err());
+        assert!(sampling.set("logit_bias", "abc:1").is_err());
+        assert!(sampling.set("stop", r#"["a","b","c","d","e"]"#).is_err());
+        assert!(sampling.set("colour", "red").is_err());
+        assert_eq!(sampling, Sampling::default());
+    }
+}
@@ -0,0 +1,178 @@
+//! Driving the REPL from a file.  Each line is a prompt or a `>`
+//! command, as it would be typed, or an expectation about the last
+//! answer:
+//!
+//! ```text
+//! # Comments and blank lines are skipped
+//! What is the capital of France?
+//! expect-contains "Paris"
+//! > retry 0
+//! expect-not-contains "London"
+//! ```
+//!
+//! The argument is a JSON string, so it can have escapes, or the rest
+//! of the line as it is.  An expectation after a request that got no
+//! answer fails.
+//!
+//! A question asked while answering, which answer to choose or whether
+//! to run a tool, is answered by the next line, as it is.  If the script
+//! has ended that is a failure, and the question gets the answer to an
+//! interrupt
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expectation {
+    Contains(String),
+    NotContains(String),
+}
+
+impl Expectation {
+    /// Parse an expectation.  `None` if `line` is not one
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let (command, argument) = line.trim().split_once(char::is_whitespace)?;
+        let argument = argument.trim();
+        let text = if argument.starts_with('"') {
+            match serde_json::from_str::<String>(argument) {
+                Ok(text) => text,
+                Err(err) => return Some(Err(format!("{line}: {err}"))),
+            }
+        } else {
+            argument.to_string()
+        };
+        match command {
+            "expect-contains" => Some(Ok(Expectation::Contains(text))),
+            "expect-not-contains" => Some(Ok(Expectation::NotContains(text))),
+            _ => None,
+        }
+    }
+
+    pub fn check(&self, answer: &str) -> Result<(), String> {
+        match self {
+            Expectation::Contains(text) if !answer.contains(text.as_str()) => {
+                Err(format!("expected the answer to contain {text:?}"))
+            }
+            Expectation::NotContains(text) if answer.contains(text.as_str()) => {
+                Err(format!("expected the answer not to contain {text:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Script {
+    lines: Vec<String>,
+    next: usize,
+    pub checked: usize,
+    pub failed: usize,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(Self::new(&text))
+    }
+
+    pub fn new(text: &str) -> Self {
+        Self {
+            lines: text.lines().map(str::to_string).collect(),
+            next: 0,
+            checked: 0,
+            failed: 0,
+        }
+    }
+
+    /// The next prompt or command.  Expectations before it are checked
+    /// against `last_answer`, `None` if there was none, and the results
+    /// displayed.  `None` at the end of the script
+    pub fn next_input(&mut self, last_answer: Option<&str>) -> Option<String> {
+        while let Some(line) = self.lines.get(self.next) {
+            self.next += 1;
+            let line_number = self.next;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match Expectation::parse(line) {
+                None => return Some(line.clone()),
+                Some(Ok(expectation)) => match last_answer {
+                    Some(answer) => expectation.check(answer),
+                    None => Err(format!("{line}: there was no answer")),
+                },
+                Some(Err(err)) => Err(err),
+            };
+            self.checked += 1;
+            match result {
+                Ok(()) => println!("\x1b[1;32mok\x1b[0m line {line_number}: {line}"),
+                Err(err) => {
+                    self.failed += 1;
+                    println!("\x1b[1;31mFAILED\x1b[0m line {line_number}: {err}");
+                }
+            }
+        }
+        None
+    }
+
+    /// The answer to `question`: the next line, as it is.  `None`, and a
+    /// failure, at the end of the script
+    pub fn next_answer(&mut self, question: &str) -> Option<String> {
+        while let Some(line) = self.lines.get(self.next) {
+            self.next += 1;
+            if !line.trim().is_empty() && !line.starts_with('#') {
+                return Some(line.clone());
+            }
+        }
+        self.checked += 1;
+        self.failed += 1;
+        println!(
+            "\x1b[1;31mFAILED\x1b[0m the script ended with no answer to {:?}",
+            question.trim()
+        );
+        None
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} expectations, {} failed", self.checked, self.failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn script() {
+        assert_eq!(
+            Expectation::parse(r#"expect-contains "a \"b\"""#),
+            Some(Ok(Expectation::Contains("a \"b\"".to_string())))
+        );
+        assert_eq!(
+            Expectation::parse("expect-not-contains two words"),
+            Some(Ok(Expectation::NotContains("two words".to_string())))
+        );
+        assert_eq!(Expectation::parse("What is expected?"), None);
+
+        let mut script = Script::new(
+            "# A comment\nHello\n\nexpect-contains \"Hi\"\nexpect-contains Bye\n> undo\n",
+        );
+        assert_eq!(script.next_input(None).as_deref(), Some("Hello"));
+        assert_eq!(
+            script.next_input(Some("Hi there")).as_deref(),
+            Some("> undo")
+        );
+        assert_eq!(script.next_input(Some("")), None);
+        assert_eq!((script.checked, script.failed), (2, 1));
+    }
+
+    #[test]
+    fn no_answer() {
+        let mut script = Script::new("Hello\nexpect-not-contains Bye\n");
+        assert_eq!(script.next_input(None).as_deref(), Some("Hello"));
+        assert_eq!(script.next_input(None), None);
+        assert_eq!((script.checked, script.failed), (1, 1));
+        let mut script = Script::new("Hello\n# Which?\n2\n");
+        script.next_input(None);
+        assert_eq!(script.next_answer("Choose 1, 2: ").as_deref(), Some("2"));
+        assert_eq!(script.next_answer("Run it? "), None);
+        assert_eq!((script.checked, script.failed), (1, 1));
+    }
+}
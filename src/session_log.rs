@@ -0,0 +1,118 @@
+//! A structured record of the session: one JSON object per line.
+//! `reply.txt` is the record for people to read, this is the one for
+//! programmes.
+use crate::chat::ChatMessage;
+use crate::compare::Summary;
+use crate::moderation::Verdict;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Things that happen in a session that are worth recording
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent<'a> {
+    /// A prompt was sent and answered.  `alternatives` are the answers
+    /// that were not chosen when more than one was asked for
+    Turn {
+        prompt: &'a str,
+        answer: &'a str,
+        model: &'a str,
+        temperature: f32,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        alternatives: Vec<&'a str>,
+        finish_reason: Option<&'a str>,
+        /// Tool calls made, and their results, while answering
+        #[serde(skip_serializing_if = "<[ChatMessage]>::is_empty")]
+        tool_messages: &'a [ChatMessage],
+        /// Excerpts added to the prompt by `--rag`
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        sources: &'a [String],
+        /// How many images were sent with the prompt
+        #[serde(skip_serializing_if = "is_zero")]
+        images: usize,
+        /// The checks of the prompt and the answer
+        #[serde(skip_serializing_if = "<[Verdict]>::is_empty")]
+        moderation: &'a [Verdict],
+        /// The answer came from the cache
+        #[serde(skip_serializing_if = "is_false")]
+        cached: bool,
+    },
+    /// More of the last answer was asked for.  `text` was appended to it
+    Continue {
+        prompt: &'a str,
+        text: &'a str,
+        finish_reason: Option<&'a str>,
+        #[serde(skip_serializing_if = "<[Verdict]>::is_empty")]
+        moderation: &'a [Verdict],
+    },
+    /// Moderation blocked the prompt, or the answer to it
+    Blocked {
+        prompt: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        answer: Option<&'a str>,
+        moderation: &'a [Verdict],
+    },
+    /// The last prompt was sent to several models.  The answer of
+    /// `chosen`, if one was, replaced the last answer
+    Compare {
+        prompt: &'a str,
+        results: Vec<Summary<'a>>,
+        chosen: Option<&'a str>,
+    },
+    /// Requests are now made with this profile's key
+    Key { profile: &'a str },
+    /// The last turn was discarded and its prompt sent again
+    Retry { prompt: &'a str, temperature: f32 },
+    /// The last turn was dropped from the context
+    Undo { prompt: &'a str, answer: &'a str },
+    /// The last turn was dropped and its prompt loaded into the editor
+    EditLast { prompt: &'a str },
+    /// An image was generated and saved in `path`
+    Image {
+        prompt: &'a str,
+        revised_prompt: Option<&'a str>,
+        path: &'a str,
+        model: &'a str,
+    },
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: u64,
+    #[serde(flatten)]
+    event: &'a SessionEvent<'a>,
+}
+
+#[derive(Debug)]
+pub struct SessionLog {
+    file: File,
+}
+
+impl SessionLog {
+    /// Open (or create) the log at `path` for appending
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: &SessionEvent) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = serde_json::to_string(&Entry { time, event }).unwrap();
+        if let Err(err) = writeln!(self.file, "{line}") {
+            eprintln!("Failed to write session log: {err}");
+        }
+    }
+}
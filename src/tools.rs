@@ -0,0 +1,211 @@
+//! Tools the model can call.  Each is a local command declared in the
+//! configuration file:
+//!
+//! ```json
+//! "tools": [{
+//!     "name": "word_count",
+//!     "description": "Count the words in a file",
+//!     "parameters": {
+//!         "type": "object",
+//!         "properties": {"path": {"type": "string"}},
+//!         "required": ["path"]
+//!     },
+//!     "command": ["wc", "-w", "{path}"],
+//!     "auto_approve": true
+//! }]
+//! ```
+//!
+//! `{name}` in the command is replaced by the argument `name`.  The
+//! command is run directly, not by a shell, so arguments cannot inject
+//! other commands.  Unless `auto_approve` is set the user is asked
+//! before each call
+use crate::chat::ToolCall;
+use crate::json_schema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::process::Command;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema for the arguments
+    #[serde(default = "empty_object")]
+    pub parameters: Value,
+    /// The programme and its arguments
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub auto_approve: bool,
+}
+
+fn empty_object() -> Value {
+    serde_json::json!({"type": "object", "properties": {}})
+}
+
+/// A tool as described to the API
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolSpec {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: FunctionSpec,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FunctionSpec {
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+impl ToolSpec {
+    pub fn function(name: &str, description: &str, parameters: Value) -> Self {
+        Self {
+            kind: "function",
+            function: FunctionSpec {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.function.name
+    }
+
+    pub fn parameters(&self) -> &Value {
+        &self.function.parameters
+    }
+}
+
+impl ToolConfig {
+    pub fn spec(&self) -> ToolSpec {
+        ToolSpec::function(&self.name, &self.description, self.parameters.clone())
+    }
+
+    /// The command line for a call with `arguments`
+    pub fn command_line(&self, arguments: &Value) -> Vec<String> {
+        self.command
+            .iter()
+            .map(|part| match arguments {
+                Value::Object(arguments) => fill(part, arguments),
+                _ => part.clone(),
+            })
+            .collect()
+    }
+
+    /// Run the command for `arguments`, returning what it wrote to
+    /// stdout and stderr
+    pub fn run(&self, arguments: &Value) -> Result<String, String> {
+        let command_line = self.command_line(arguments);
+        let (programme, args) = command_line
+            .split_first()
+            .ok_or_else(|| format!("{}: empty command", self.name))?;
+        let output = Command::new(programme)
+            .args(args)
+            .output()
+            .map_err(|err| format!("{programme}: {err}"))?;
+        let mut result = String::from_utf8_lossy(&output.stdout).to_string();
+        result.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !output.status.success() {
+            result.push_str(&format!("\n({programme} failed: {})", output.status));
+        }
+        Ok(result)
+    }
+}
+
+/// `template` with each `{name}` replaced by that argument, in one pass,
+/// so an argument's value is never itself filled in
+fn fill(template: &str, arguments: &Map<String, Value>) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = after
+            .find('}')
+            .and_then(|end| Some((end, arguments.get(&after[..end])?)));
+        match placeholder {
+            Some((end, value)) => {
+                match value {
+                    Value::String(s) => result.push_str(s),
+                    v => result.push_str(&v.to_string()),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A tool call from the model, checked against the tool's declaration
+pub struct PreparedCall<'a> {
+    pub tool: &'a ToolConfig,
+    pub arguments: Value,
+}
+
+/// Find the tool for `call` and check its arguments.  The error is
+/// returned to the model so it can try again
+pub fn prepare<'a>(tools: &'a [ToolConfig], call: &ToolCall) -> Result<PreparedCall<'a>, String> {
+    let tool = tools
+        .iter()
+        .find(|t| t.name == call.function.name)
+        .ok_or_else(|| format!("There is no tool called {:?}", call.function.name))?;
+    let arguments: Value = serde_json::from_str(&call.function.arguments)
+        .map_err(|err| format!("Arguments are not JSON: {err}"))?;
+    json_schema::validate(&tool.parameters, &arguments)
+        .map_err(|err| format!("Arguments do not match the parameters:\n{err}"))?;
+    Ok(PreparedCall { tool, arguments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::FunctionCall;
+    #[test]
+    fn prepare_and_run() {
+        let tools: Vec<ToolConfig> = serde_json::from_str(
+            r#"[{"name": "echo",
+                 "parameters": {"type": "object",
+                                "properties": {"text": {"type": "string"}},
+                                "required": ["text"]},
+                 "command": ["echo", "said: {text}"]}]"#,
+        )
+        .unwrap();
+        let call = |name: &str, arguments: &str| ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        };
+        let prepared = prepare(&tools, &call("echo", r#"{"text": "hi; rm -rf /"}"#)).unwrap();
+        assert_eq!(
+            prepared.tool.run(&prepared.arguments).unwrap(),
+            "said: hi; rm -rf /\n"
+        );
+        assert!(prepare(&tools, &call("missing", "{}")).is_err());
+        assert!(prepare(&tools, &call("echo", "{}")).is_err());
+        assert!(prepare(&tools, &call("echo", "not json")).is_err());
+    }
+
+    #[test]
+    fn placeholders_are_filled_once() {
+        let tool: ToolConfig = serde_json::from_str(
+            r#"{"name": "copy", "command": ["cp", "{from}", "{to}", "{n}-{missing}"]}"#,
+        )
+        .unwrap();
+        let arguments = serde_json::json!({"from": "{to}", "to": "out.txt", "n": 2});
+        assert_eq!(
+            tool.command_line(&arguments),
+            ["cp", "{to}", "out.txt", "2-{missing}"]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Tokens used by requests, and what they cost.  Every response from
+//! the API that says how many tokens it used is added to the ledger,
+//! `usage.jsonl` unless the `usage` section of the configuration or
+//! `--ledger` says otherwise, with the profile, organization and project
+//! it was made for, so costs can be attributed
+use crate::profiles::Credentials;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The `usage` section of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub ledger: PathBuf,
+    /// Add to the ledger.  `--no-ledger` turns it off too
+    pub record: bool,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            ledger: PathBuf::from("usage.jsonl"),
+            record: true,
+        }
+    }
+}
+
+/// The `usage` of a response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// US dollars per million prompt and completion tokens.  Longer names
+/// first, so the most specific prefix is found.  From OpenAI's price
+/// list, so only an estimate once prices change
+const PRICES: [(&str, f64, f64); 17] = [
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-3.5-turbo-instruct", 1.50, 2.00),
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("o1-mini", 1.10, 4.40),
+    ("o1", 15.00, 60.00),
+    ("o3-mini", 1.10, 4.40),
+    ("o3", 2.00, 8.00),
+    ("o4-mini", 1.10, 4.40),
+    ("davinci-002", 2.00, 2.00),
+    ("babbage-002", 0.40, 0.40),
+    ("text-davinci-003", 20.00, 20.00),
+];
+
+/// What `usage` cost with `model`, if its price is known.  Fine-tuned
+/// models are priced as their base model, which they are not quite
+pub fn cost(model: &str, usage: &Usage) -> Option<f64> {
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    let (_, prompt, completion) = PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))?;
+    Some(
+        (usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion)
+            / 1_000_000.0,
+    )
+}
+
+/// A cost for display
+pub fn format_cost(cost: Option<f64>) -> String {
+    match cost {
+        Some(cost) => format!("${cost:.6}"),
+        None => "unknown".to_string(),
+    }
+}
+
+/// A line of the ledger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: u64,
+    pub profile: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    pub model: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+/// Usage added up by profile, project and model
+#[derive(Debug, Default)]
+pub struct Totals(BTreeMap<(String, String, String), (Usage, Option<f64>)>);
+
+impl Totals {
+    pub fn add(&mut self, entry: &Entry) {
+        let key = (
+            entry.profile.clone(),
+            entry.project.clone().unwrap_or_default(),
+            entry.model.clone(),
+        );
+        let (usage, cost) = self.0.entry(key).or_insert((Usage::default(), Some(0.0)));
+        usage.add(&entry.usage);
+        // Unknown if any of it is
+        *cost = cost.zip(entry.cost).map(|(a, b)| a + b);
+    }
+
+    pub fn table(&self) -> String {
+        let mut result = format!(
+            "{:<12} {:<20} {:<24} {:>10} {:>10} {:>12}\n",
+            "PROFILE", "PROJECT", "MODEL", "IN", "OUT", "COST"
+        );
+        for ((profile, project, model), (usage, cost)) in self.0.iter() {
+            result.push_str(&format!(
+                "{:<12} {:<20} {:<24} {:>10} {:>10} {:>12}\n",
+                profile,
+                if project.is_empty() { "-" } else { project },
+                model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                format_cost(*cost)
+            ));
+        }
+        result
+    }
+}
+
+pub struct Ledger {
+    path: PathBuf,
+    /// In this session
+    totals: Mutex<Totals>,
+}
+
+impl Ledger {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            totals: Mutex::new(Totals::default()),
+        }
+    }
+
+    /// Record the usage in `response`, a response body, if it has any
+    pub fn record_response(&self, credentials: &Credentials, response: &[u8]) {
+        let Ok(json) = serde_json::from_slice::<Value>(response) else {
+            return;
+        };
+        let Some(Ok(usage)) = json.get("usage").map(Usage::deserialize) else {
+            return;
+        };
+        let model = json["model"].as_str().unwrap_or("unknown").to_string();
+        let entry = Entry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            profile: credentials.profile.clone(),
+            organization: credentials.organization.clone(),
+            project: credentials.project.clone(),
+            cost: cost(&model, &usage),
+            model,
+            usage,
+        };
+        self.totals.lock().unwrap().add(&entry);
+        let line = serde_json::to_string(&entry).unwrap() + "\n";
+        let written = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(err) = written {
+            eprintln!("{}: {err}", self.path.display());
+        }
+    }
+
+    /// For `> usage`
+    pub fn session_table(&self) -> String {
+        self.totals.lock().unwrap().table()
+    }
+}
+
+/// For `usage`.  Displays everything in the ledger at `path`
+pub fn run_usage(path: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut totals = Totals::default();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(line)
+            .map_err(|err| format!("{} line {}: {err}", path.display(), i + 1))?;
+        totals.add(&entry);
+    }
+    print!("{}", totals.table());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn costs() {
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            total_tokens: 1_500_000,
+        };
+        assert_eq!(cost("gpt-4o-mini-2024-07-18", &usage), Some(0.45));
+        assert_eq!(cost("gpt-4o", &usage), Some(7.5));
+        assert_eq!(cost("ft:gpt-4o-mini:org::abc", &usage), Some(0.45));
+        assert_eq!(cost("unknown-model", &usage), None);
+        assert_eq!(format_cost(Some(0.000123)), "$0.000123");
+    }
+
+    #[test]
+    fn ledger() {
+        use crate::api_key::ApiKey;
+        let path = std::env::temp_dir().join(format!("usage_test_{}.jsonl", std::process::id()));
+        _ = fs::remove_file(&path);
+        let ledger = Ledger::new(&path);
+        let mut work = Credentials::new(ApiKey::new("sk-work"));
+        work.profile = "work".to_string();
+        work.project = Some("proj_1".to_string());
+        let response = br#"{"model": "gpt-4o-mini", "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}}"#;
+        ledger.record_response(&work, response);
+        ledger.record_response(&work, response);
+        ledger.record_response(
+            &Credentials::new(ApiKey::new("sk-other")),
+            br#"{"model": "x"}"#,
+        );
+        ledger.record_response(&work, b"not JSON");
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(!text.contains("sk-work"));
+        let table = ledger.session_table();
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("work         proj_1"), "{table}");
+        assert!(
+            row.contains(" 20 ") && row.ends_with("$0.000009"),
+            "{table}"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
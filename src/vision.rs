@@ -0,0 +1,146 @@
+//! Local images sent with a prompt, to chat models that accept them.
+//! An image is attached with `@path` in the prompt, or `> img path`.  It
+//! is sent base64 encoded in a `data:` URL.  Images larger than
+//! `max_side` pixels on a side are scaled down first, and anything over
+//! the API's 20MB limit is refused
+use crate::chat::ImageUrl;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// The most the API accepts for one image
+pub const MAX_BYTES: usize = 20 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct VisionOptions {
+    /// "low", "high" or "auto".  `None` leaves it to the API
+    pub detail: Option<String>,
+    /// Scale images down so neither side is larger than this
+    pub max_side: Option<u32>,
+}
+
+/// The MIME type for an image file, from its extension
+fn mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Check a detail level is one the API knows
+pub fn check_detail(detail: &str) -> Result<(), String> {
+    match detail {
+        "low" | "high" | "auto" => Ok(()),
+        _ => Err(format!("Detail is low, high or auto, not {detail}")),
+    }
+}
+
+/// Read the image at `path`, scale it down if needed, and encode it
+pub fn attach(path: &Path, options: &VisionOptions) -> Result<ImageUrl, String> {
+    let mime = mime_type(path)
+        .ok_or_else(|| format!("{}: not a PNG, JPEG, GIF or WEBP image", path.display()))?;
+    let mut bytes = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut mime = mime;
+    if let Some(max_side) = options.max_side {
+        let image =
+            image::load_from_memory(&bytes).map_err(|err| format!("{}: {err}", path.display()))?;
+        if image.width() > max_side || image.height() > max_side {
+            let scaled = image.resize(max_side, max_side, FilterType::Lanczos3);
+            println!(
+                "Scaled {} from {}x{} to {}x{}",
+                path.display(),
+                image.width(),
+                image.height(),
+                scaled.width(),
+                scaled.height()
+            );
+            let format = if mime == "image/jpeg" {
+                ImageFormat::Jpeg
+            } else {
+                mime = "image/png";
+                ImageFormat::Png
+            };
+            let mut encoded = Cursor::new(Vec::new());
+            scaled
+                .write_to(&mut encoded, format)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            bytes = encoded.into_inner();
+        }
+    }
+    if bytes.len() > MAX_BYTES {
+        return Err(format!(
+            "{} is {} bytes, more than the limit of {MAX_BYTES}.  Try --max-image-side",
+            path.display(),
+            bytes.len()
+        ));
+    }
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&bytes);
+    Ok(ImageUrl {
+        url: format!("data:{mime};base64,{encoded}"),
+        detail: options.detail.clone(),
+    })
+}
+
+/// Find `@path` attachments in `prompt`: words starting with '@' that
+/// name an image file that exists.  Returns the prompt without them,
+/// and the paths
+pub fn extract_attachments(prompt: &str) -> (String, Vec<PathBuf>) {
+    let mut paths = Vec::new();
+    let mut words = Vec::new();
+    for word in prompt.split(' ') {
+        match word.strip_prefix('@').map(PathBuf::from) {
+            Some(path) if mime_type(&path).is_some() && path.is_file() => paths.push(path),
+            _ => words.push(word),
+        }
+    }
+    if paths.is_empty() {
+        (prompt.to_string(), paths)
+    } else {
+        (words.join(" ").trim().to_string(), paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn attach_and_scale() {
+        let path = std::env::temp_dir().join(format!("vision_test_{}.png", std::process::id()));
+        ImageBuffer::from_pixel(40, 20, Rgb([255u8, 0, 0]))
+            .save(&path)
+            .unwrap();
+
+        let (prompt, paths) =
+            extract_attachments(&format!("What colour is @{} ? @someone", path.display()));
+        assert_eq!(prompt, "What colour is ? @someone");
+        assert_eq!(paths, vec![path.clone()]);
+
+        let options = VisionOptions {
+            detail: Some("low".to_string()),
+            max_side: Some(10),
+        };
+        let image_url = attach(&path, &options).unwrap();
+        assert_eq!(image_url.detail.as_deref(), Some("low"));
+        let encoded = image_url
+            .url
+            .strip_prefix("data:image/png;base64,")
+            .unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .unwrap();
+        let scaled = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((scaled.width(), scaled.height()), (10, 5));
+
+        assert!(attach(Path::new("notes.txt"), &options).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,689 @@
+//! Built in tools for chat models: `read_file`, `list_dir`, `grep`,
+//! `write_file` and `run_command`.  They are confined to a workspace
+//! directory.  Paths that resolve outside it, or that match the deny
+//! list, are refused.  `write_file` always displays the change and asks
+//! before writing.  `run_command` runs a programme (not a shell) in the
+//! workspace, with a time limit and an empty environment, and asks
+//! first unless it is listed in `auto_approve`.  Only the programmes in
+//! `allow_commands` may be run, as most others, from shells to `sed`,
+//! `find` and `git`, can be made to run anything.  Every argument that
+//! is not a flag must be a path in the workspace, as must the value of a
+//! `--flag=value` and what follows a short flag, as in `-o/tmp/x`.
+//! Configured in the configuration file:
+//!
+//! ```json
+//! "workspace": {
+//!     "root": ".",
+//!     "deny": [".git", ".env", "*.pem"],
+//!     "command_timeout": 30,
+//!     "max_output": 20000,
+//!     "auto_approve": ["read_file", "list_dir", "grep"],
+//!     "allow_commands": ["cat", "head", "tail", "wc", "ls", "grep", "sort"]
+//! }
+//! ```
+use crate::json_schema;
+use crate::tools::ToolSpec;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Stop `grep` after this many matches
+const MAX_MATCHES: usize = 200;
+
+/// The programmes `run_command` may run unless the configuration says
+/// otherwise.  None of them can be made to run another programme
+const COMMANDS: [&str; 22] = [
+    "cat",
+    "head",
+    "tail",
+    "wc",
+    "ls",
+    "grep",
+    "sort",
+    "uniq",
+    "cut",
+    "tr",
+    "diff",
+    "cmp",
+    "file",
+    "stat",
+    "du",
+    "md5sum",
+    "sha256sum",
+    "echo",
+    "printenv",
+    "pwd",
+    "date",
+    "true",
+];
+
+/// The only environment `run_command`'s programmes get, with HOME the
+/// workspace
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkspaceConfig {
+    /// The directory the tools are confined to
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+    /// File names (with `*` wild cards) the tools cannot see or touch.
+    /// Matched against every component of the path
+    #[serde(default = "default_deny")]
+    pub deny: Vec<String>,
+    /// Seconds before `run_command` is killed
+    #[serde(default = "default_timeout")]
+    pub command_timeout: u64,
+    /// Output longer than this many bytes is truncated
+    #[serde(default = "default_max_output")]
+    pub max_output: usize,
+    /// Tools that run without asking.  `write_file` always asks
+    #[serde(default = "default_auto_approve")]
+    pub auto_approve: Vec<String>,
+    /// The programmes `run_command` may run, by name.  Any that can run
+    /// other programmes, or code, escapes the workspace
+    #[serde(default = "default_commands")]
+    pub allow_commands: Vec<String>,
+}
+
+fn default_deny() -> Vec<String> {
+    [".git", ".env", "*.pem", "*.key", "id_rsa*", "id_ed25519*"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn default_commands() -> Vec<String> {
+    COMMANDS.iter().map(|s| s.to_string()).collect()
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_max_output() -> usize {
+    20_000
+}
+
+fn default_auto_approve() -> Vec<String> {
+    ["read_file", "list_dir", "grep"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        Self {
+            root: None,
+            deny: default_deny(),
+            command_timeout: default_timeout(),
+            max_output: default_max_output(),
+            auto_approve: default_auto_approve(),
+            allow_commands: default_commands(),
+        }
+    }
+}
+
+pub struct Workspace {
+    /// Canonical
+    root: PathBuf,
+    config: WorkspaceConfig,
+    specs: Vec<ToolSpec>,
+}
+
+impl Workspace {
+    pub fn new(config: WorkspaceConfig) -> Result<Self, String> {
+        let root = config.root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let root = root
+            .canonicalize()
+            .map_err(|err| format!("Workspace {}: {err}", root.display()))?;
+        Ok(Self {
+            root,
+            config,
+            specs: specs(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn specs(&self) -> &[ToolSpec] {
+        &self.specs
+    }
+
+    pub fn handles(&self, name: &str) -> bool {
+        self.specs.iter().any(|s| s.name() == name)
+    }
+
+    /// Run the built in tool `name`.  `confirm` is asked before
+    /// anything that is not auto approved.  The result, or the error, is
+    /// for the model
+    pub fn call(
+        &self,
+        name: &str,
+        arguments: &Value,
+        confirm: &mut dyn FnMut(&str) -> bool,
+    ) -> String {
+        let spec = match self.specs.iter().find(|s| s.name() == name) {
+            Some(spec) => spec,
+            None => return format!("There is no tool called {name:?}"),
+        };
+        if let Err(err) = json_schema::validate(spec.parameters(), arguments) {
+            return format!("Arguments do not match the parameters:\n{err}");
+        }
+        let approved = self.config.auto_approve.iter().any(|a| a == name);
+        let result = match name {
+            "read_file" => self.read_file(arguments),
+            "list_dir" => self.list_dir(arguments),
+            "grep" => self.grep(arguments),
+            "write_file" => self.write_file(arguments, confirm),
+            "run_command" => {
+                if approved || confirm("Run it? [y/N] ") {
+                    self.run_command(arguments)
+                } else {
+                    Err("The user declined to run the command".to_string())
+                }
+            }
+            _ => Err(format!("There is no tool called {name:?}")),
+        };
+        let result = result.unwrap_or_else(|err| format!("Error: {err}"));
+        truncate(result, self.config.max_output)
+    }
+
+    /// Resolve `path`, relative to the root, and check it is inside the
+    /// workspace and not denied.  The path need not exist, but its
+    /// parent must
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let joined = self.root.join(path);
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                // A new file.  Resolve the parent
+                let parent = joined
+                    .parent()
+                    .ok_or_else(|| format!("{path}: no parent directory"))?;
+                let name = joined
+                    .file_name()
+                    .ok_or_else(|| format!("{path}: not a file name"))?;
+                parent
+                    .canonicalize()
+                    .map_err(|err| format!("{path}: {err}"))?
+                    .join(name)
+            }
+        };
+        let relative = resolved
+            .strip_prefix(&self.root)
+            .map_err(|_| format!("{path} is outside the workspace"))?;
+        if self.denied(relative) {
+            return Err(format!("{path} is not allowed"));
+        }
+        Ok(resolved)
+    }
+
+    fn denied(&self, relative: &Path) -> bool {
+        relative.components().any(|c| match c {
+            Component::Normal(name) => {
+                let name = name.to_string_lossy();
+                self.config.deny.iter().any(|p| matches(p, &name))
+            }
+            _ => false,
+        })
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    fn read_file(&self, arguments: &Value) -> Result<String, String> {
+        let path = self.resolve(string(arguments, "path"))?;
+        let text = fs::read_to_string(&path).map_err(|err| err.to_string())?;
+        let start = arguments["start_line"].as_u64().unwrap_or(1).max(1) as usize;
+        let end = arguments["end_line"].as_u64().map(|e| e as usize);
+        Ok(text
+            .lines()
+            .enumerate()
+            .skip(start - 1)
+            .take_while(|(i, _)| end.map(|e| *i < e).unwrap_or(true))
+            .map(|(i, line)| format!("{:>5} {line}\n", i + 1))
+            .collect())
+    }
+
+    fn list_dir(&self, arguments: &Value) -> Result<String, String> {
+        let path = self.resolve(arguments["path"].as_str().unwrap_or("."))?;
+        let mut entries: Vec<String> = fs::read_dir(&path)
+            .map_err(|err| err.to_string())?
+            .filter_map(Result::ok)
+            .filter(|e| !self.denied(Path::new(&e.file_name())))
+            .map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                if e.path().is_dir() {
+                    format!("{name}/")
+                } else {
+                    name
+                }
+            })
+            .collect();
+        entries.sort();
+        Ok(entries.join("\n"))
+    }
+
+    fn grep(&self, arguments: &Value) -> Result<String, String> {
+        let pattern = string(arguments, "pattern");
+        let ignore_case = arguments["ignore_case"].as_bool().unwrap_or(false);
+        let needle = if ignore_case {
+            pattern.to_lowercase()
+        } else {
+            pattern.to_string()
+        };
+        let start = self.resolve(arguments["path"].as_str().unwrap_or("."))?;
+        let mut matches = Vec::new();
+        let mut stack = vec![start];
+        while let Some(path) = stack.pop() {
+            if path.is_dir() {
+                let mut children: Vec<PathBuf> = match fs::read_dir(&path) {
+                    Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+                    Err(_) => continue,
+                };
+                children.sort();
+                children.reverse();
+                stack.extend(children.into_iter().filter(|c| {
+                    // Do not follow links out of the workspace
+                    c.canonicalize()
+                        .map(|c| c.starts_with(&self.root))
+                        .unwrap_or(false)
+                        && !self.denied(Path::new(&self.relative(c)))
+                }));
+                continue;
+            }
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                // Binary, or unreadable
+                Err(_) => continue,
+            };
+            for (i, line) in text.lines().enumerate() {
+                let found = if ignore_case {
+                    line.to_lowercase().contains(&needle)
+                } else {
+                    line.contains(&needle)
+                };
+                if found {
+                    matches.push(format!("{}:{}: {line}", self.relative(&path), i + 1));
+                    if matches.len() == MAX_MATCHES {
+                        matches.push(format!("(stopped after {MAX_MATCHES} matches)"));
+                        return Ok(matches.join("\n"));
+                    }
+                }
+            }
+        }
+        if matches.is_empty() {
+            Ok("No matches".to_string())
+        } else {
+            Ok(matches.join("\n"))
+        }
+    }
+
+    fn write_file(
+        &self,
+        arguments: &Value,
+        confirm: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String, String> {
+        let path = self.resolve(string(arguments, "path"))?;
+        let content = string(arguments, "content");
+        let old = fs::read_to_string(&path).unwrap_or_default();
+        println!("--- {}", self.relative(&path));
+        print!("{}", diff(&old, content));
+        if !confirm("Write it? [y/N] ") {
+            return Err("The user declined the change".to_string());
+        }
+        fs::write(&path, content).map_err(|err| err.to_string())?;
+        Ok(format!(
+            "Wrote {} bytes to {}",
+            content.len(),
+            self.relative(&path)
+        ))
+    }
+
+    /// Refuse programmes that are not allowed, and arguments, or the
+    /// values of options, that are not paths in the workspace
+    fn check_command(&self, programme: &str, args: &[&str]) -> Result<(), String> {
+        if !self.config.allow_commands.iter().any(|c| c == programme) {
+            return Err(format!(
+                "{programme} is not allowed.  The commands that are: {}.  \
+                 Others can be added to `allow_commands`",
+                self.config.allow_commands.join(", ")
+            ));
+        }
+        for arg in args {
+            let path = match (arg.strip_prefix("--"), arg.strip_prefix('-')) {
+                // `--name=value`, or a flag, or the end of the options
+                (Some(long), _) => match long.split_once('=') {
+                    Some((_, value)) => value,
+                    None => continue,
+                },
+                // Short flags, or one with its value attached.  Which it
+                // is is up to the programme, so the rest is checked
+                (None, Some(short)) => short.get(1..).unwrap_or_default(),
+                (None, None) => arg,
+            };
+            if !path.is_empty() {
+                self.resolve(path).map_err(|err| format!("{arg}: {err}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_command(&self, arguments: &Value) -> Result<String, String> {
+        let programme = string(arguments, "programme");
+        let args: Vec<&str> = arguments["args"]
+            .as_array()
+            .map(|a| a.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        self.check_command(programme, &args)?;
+        let mut child = Command::new(programme)
+            .args(&args)
+            .current_dir(&self.root)
+            .env_clear()
+            .env("PATH", PATH)
+            .env("HOME", &self.root)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("{programme}: {err}"))?;
+
+        // Read the output in threads so a full pipe cannot block the child
+        let mut stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let out = std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            _ = stdout.read_to_end(&mut buffer);
+            buffer
+        });
+        let err = std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            _ = stderr.read_to_end(&mut buffer);
+            buffer
+        });
+        let timeout = Duration::from_secs(self.config.command_timeout);
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait().map_err(|err| err.to_string())? {
+                Some(status) => break Some(status),
+                None if started.elapsed() > timeout => {
+                    _ = child.kill();
+                    _ = child.wait();
+                    break None;
+                }
+                None => std::thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        let mut result = String::from_utf8_lossy(&out.join().unwrap_or_default()).to_string();
+        result.push_str(&String::from_utf8_lossy(&err.join().unwrap_or_default()));
+        match status {
+            Some(status) if status.success() => (),
+            Some(status) => result.push_str(&format!("\n({programme} failed: {status})")),
+            None => result.push_str(&format!(
+                "\n({programme} killed after {} seconds)",
+                self.config.command_timeout
+            )),
+        }
+        Ok(result)
+    }
+}
+
+fn string<'a>(arguments: &'a Value, name: &str) -> &'a str {
+    arguments[name].as_str().unwrap_or_default()
+}
+
+/// Cut `text` to at most `max` bytes, on a character boundary
+fn truncate(mut text: String, max: usize) -> String {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let dropped = text.len() - end;
+    text.truncate(end);
+    text.push_str(&format!("\n(truncated, {dropped} more bytes)"));
+    text
+}
+
+/// Match `name` against `pattern`, where `*` matches anything
+fn matches(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let mut rest = match name.strip_prefix(parts[0]) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let last = parts[parts.len() - 1];
+    for part in parts[1..parts.len() - 1].iter() {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// The lines removed (`-`) and added (`+`) to get from `old` to `new`
+pub fn diff(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    // Longest common subsequence, unless that is too expensive
+    if a.len() * b.len() > 4_000_000 {
+        let mut result = String::new();
+        a.iter().for_each(|l| result.push_str(&format!("-{l}\n")));
+        b.iter().for_each(|l| result.push_str(&format!("+{l}\n")));
+        return result;
+    }
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut result = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            result.push_str(&format!("\x1b[31m-{}\x1b[0m\n", a[i]));
+            i += 1;
+        } else {
+            result.push_str(&format!("\x1b[32m+{}\x1b[0m\n", b[j]));
+            j += 1;
+        }
+    }
+    result
+}
+
+fn specs() -> Vec<ToolSpec> {
+    vec![
+        ToolSpec::function(
+            "read_file",
+            "Read a text file in the workspace, with line numbers",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": {"type": "string", "description": "Relative to the workspace"},
+                    "start_line": {"type": "integer", "minimum": 1},
+                    "end_line": {"type": "integer", "minimum": 1}
+                },
+                "required": ["path"]
+            }),
+        ),
+        ToolSpec::function(
+            "list_dir",
+            "List a directory in the workspace.  Directories end in /",
+            json!({
+                "type": "object",
+                "properties": {"path": {"type": "string", "description": "Defaults to ."}}
+            }),
+        ),
+        ToolSpec::function(
+            "grep",
+            "Search the text files under a path for lines containing a string",
+            json!({
+                "type": "object",
+                "properties": {
+                    "pattern": {"type": "string", "minLength": 1},
+                    "path": {"type": "string", "description": "Defaults to ."},
+                    "ignore_case": {"type": "boolean"}
+                },
+                "required": ["pattern"]
+            }),
+        ),
+        ToolSpec::function(
+            "write_file",
+            "Replace the contents of a file in the workspace.  The user must approve",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": {"type": "string"},
+                    "content": {"type": "string"}
+                },
+                "required": ["path", "content"]
+            }),
+        ),
+        ToolSpec::function(
+            "run_command",
+            "Run a programme in the workspace directory.  Not a shell",
+            json!({
+                "type": "object",
+                "properties": {
+                    "programme": {"type": "string"},
+                    "args": {"type": "array", "items": {"type": "string"}}
+                },
+                "required": ["programme"]
+            }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workspace(name: &str) -> (Workspace, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("workspace_{name}_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("src/a.txt"), "one\ntwo\nthree\n").unwrap();
+        fs::write(dir.join(".git/config"), "secret\n").unwrap();
+        let config = WorkspaceConfig {
+            root: Some(dir.clone()),
+            ..Default::default()
+        };
+        (Workspace::new(config).unwrap(), dir)
+    }
+
+    #[test]
+    fn confined_to_the_workspace() {
+        let (workspace, dir) = workspace("confined");
+        let mut never = |_: &str| false;
+        assert!(workspace.resolve("src/a.txt").is_ok());
+        assert!(workspace.resolve("src/new.txt").is_ok());
+        assert!(workspace.resolve("../outside").is_err());
+        assert!(workspace.resolve("/etc/passwd").is_err());
+        assert!(workspace.resolve(".git/config").is_err());
+
+        let read = workspace.call(
+            "read_file",
+            &json!({"path": "src/a.txt", "start_line": 2}),
+            &mut never,
+        );
+        assert_eq!(read, "    2 two\n    3 three\n");
+        let list = workspace.call("list_dir", &json!({}), &mut never);
+        assert_eq!(list, "src/");
+        let grep = workspace.call(
+            "grep",
+            &json!({"pattern": "T", "ignore_case": true}),
+            &mut never,
+        );
+        assert_eq!(grep, "src/a.txt:2: two\nsrc/a.txt:3: three");
+        let write = workspace.call(
+            "write_file",
+            &json!({"path": "src/a.txt", "content": "x"}),
+            &mut never,
+        );
+        assert!(write.starts_with("Error: The user declined"));
+        let run = workspace.call(
+            "run_command",
+            &json!({"programme": "cat", "args": ["../x"]}),
+            &mut |_: &str| true,
+        );
+        assert!(run.contains("outside the workspace"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commands_are_confined() {
+        let (workspace, dir) = workspace("commands");
+        fs::write(dir.join(".env"), "OPENAI_API_KEY=sk-secret\n").unwrap();
+        let mut yes = |_: &str| true;
+        let mut run = |programme: &str, args: &[&str]| {
+            workspace.call(
+                "run_command",
+                &json!({"programme": programme, "args": args}),
+                &mut yes,
+            )
+        };
+        assert!(run("cat", &[".env"]).contains(".env is not allowed"));
+        assert!(run("cat", &["src/../.git/config"]).contains("not allowed"));
+        assert!(run("grep", &["--file=../x", "src"]).contains("outside the workspace"));
+        assert!(run("grep", &["-f/etc/passwd", "src"]).contains("outside the workspace"));
+        assert!(run("sort", &["-o/tmp/sorted", "src/a.txt"]).contains("outside the workspace"));
+        assert!(run("sh", &["-c", "cat ../../etc/passwd"]).contains("sh is not allowed"));
+        assert!(run("/bin/cat", &["src/a.txt"]).contains("is not allowed"));
+        assert!(
+            run("sed", &["-n", "1e cat /etc/passwd", "src/a.txt"]).contains("sed is not allowed")
+        );
+        assert!(run("find", &[".", "-exec", "sh", ";"]).contains("find is not allowed"));
+        assert_eq!(run("cat", &["-n", "src/a.txt"]).lines().count(), 3);
+        assert_eq!(run("head", &["-n2", "src/a.txt"]), "one\ntwo\n");
+
+        // Nothing from this environment, such as the API key, is passed on
+        let environment = run("printenv", &[]);
+        let mut names: Vec<&str> = environment
+            .lines()
+            .filter_map(|line| line.split_once('=').map(|(name, _)| name))
+            .collect();
+        names.sort();
+        assert_eq!(names, ["HOME", "PATH"], "{environment}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn helpers() {
+        assert!(matches("*.pem", "server.pem"));
+        assert!(matches("id_rsa*", "id_rsa.pub"));
+        assert!(!matches("*.pem", "pem"));
+        assert_eq!(
+            truncate("abcdef".to_string(), 3),
+            "abc\n(truncated, 3 more bytes)"
+        );
+        assert_eq!(
+            diff("a\nb\n", "a\nc\n"),
+            "\x1b[31m-b\x1b[0m\n\x1b[32m+c\x1b[0m\n"
+        );
+    }
+}
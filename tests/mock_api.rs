@@ -0,0 +1,797 @@
+//! End-to-end tests: the programme against the mock server, which is
+//! not built into the programme, so runs here
+use std::fs;
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// The mock server's source, shared with the programme's own tests
+#[allow(dead_code)]
+#[path = "../src/mock_server.rs"]
+mod mock_server;
+#[allow(dead_code)]
+#[path = "../src/model_example_data.rs"]
+mod model_example_data;
+
+use mock_server::MockServer as Mock;
+
+const BIN: &str = env!("CARGO_BIN_EXE_open_ai_chat_gpt3");
+
+/// A mock server with `fixtures`, which are kept in `dir` too
+fn mock(dir: &Path, fixtures: &str) -> Mock {
+    let path = dir.join("fixtures.json");
+    fs::write(&path, fixtures).unwrap();
+    Mock::start(0, mock_server::load_fixtures(&path).unwrap()).unwrap()
+}
+
+/// An empty directory for the files the programme writes, removed when
+/// dropped
+struct Scratch(PathBuf);
+
+impl Deref for Scratch {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Scratch {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn scratch(name: &str) -> Scratch {
+    let dir = std::env::temp_dir().join(format!("open_ai_test_{name}_{}", std::process::id()));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Scratch(dir)
+}
+
+/// The programme, in `dir`, with a key and the mock's URL, and `args`
+fn command(dir: &Path, mock: &Mock, args: &[&str]) -> Command {
+    let mut command = Command::new(BIN);
+    command
+        .args(["--api-key", "sk-test", "--base-url", &mock.url])
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null());
+    command
+}
+
+/// Run the programme and wait for it to finish
+fn run(dir: &Path, mock: &Mock, args: &[&str]) -> Output {
+    command(dir, mock, args).output().unwrap()
+}
+
+#[test]
+fn one_shot() {
+    let dir = scratch("one_shot");
+    let mock = mock(&dir, r#"[{"path": "/v1/completions", "answer": " Paris"}]"#);
+    let output = run(
+        &dir,
+        &mock,
+        &[
+            "--model",
+            "davinci",
+            "--prompt",
+            "What is the capital of France?",
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Paris\n");
+}
+
+#[test]
+fn error_response() {
+    let dir = scratch("error_response");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "status": 401,
+             "body": {"error": {"message": "Incorrect API key provided"}}}]"#,
+    );
+    let output = run(&dir, &mock, &["--model", "gpt-4o", "--prompt", "Hello"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Incorrect API key provided"));
+}
+
+#[test]
+fn logprobs_limits() {
+    let dir = scratch("logprobs_limits");
+    let mock = mock(&dir, "[]");
+    let run = |model: &str, logprobs: &str| {
+        run(
+            &dir,
+            &mock,
+            &[
+                "--model",
+                model,
+                "--logprobs",
+                logprobs,
+                "--prompt",
+                "Hello",
+            ],
+        )
+    };
+    let output = run("gpt-4o", "21");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("0..=20"));
+    let output = run("davinci-002", "6");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("at most 5"));
+    assert!(run("gpt-4o", "10").status.success());
+}
+
+#[test]
+fn repl() {
+    let dir = scratch("repl");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "2+2", "answer": "Four"}]"#,
+    );
+    let mut child = command(&dir, &mock, &["--model", "gpt-4o"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"What is 2+2?\n> undo\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("This is a mock answer."), "{stdout}");
+    assert!(stdout.contains("Four"), "{stdout}");
+
+    let log = fs::read_to_string(dir.join("session.jsonl")).unwrap();
+    let events: Vec<serde_json::Value> = log
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, ["turn", "turn", "undo"]);
+    assert_eq!(events[1]["answer"], "Four");
+    assert_eq!(events[2]["prompt"], "What is 2+2?");
+}
+
+#[test]
+fn record_and_replay() {
+    let dir = scratch("record_and_replay");
+    let cassette = dir.join("cassette");
+    let ask = |args: &[&str], prompt: &str| {
+        Command::new(BIN)
+            .args(["--api-key", "sk-secret-key", "--model", "gpt-4o"])
+            .args(args)
+            .args(["--prompt", prompt])
+            .current_dir(&dir)
+            .output()
+            .unwrap()
+    };
+    {
+        let mock = mock(
+            &dir,
+            r#"[{"path": "/v1/chat/completions", "answer": "Recorded"}]"#,
+        );
+        let cassette = cassette.to_str().unwrap();
+        let output = ask(&["--base-url", &mock.url, "--record", cassette], "Hi");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Recorded\n");
+    }
+    for entry in fs::read_dir(&cassette).unwrap() {
+        let text = fs::read_to_string(entry.unwrap().path()).unwrap();
+        assert!(!text.contains("sk-secret-key"), "{text}");
+    }
+
+    // The mock has gone
+    let output = ask(&["--replay", cassette.to_str().unwrap()], "Hi");
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Recorded\n");
+
+    let output = ask(&["--replay", cassette.to_str().unwrap()], "Bye");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nothing recorded"));
+}
+
+#[test]
+fn cache() {
+    let dir = scratch("cache");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "answer": "First", "times": 1}]"#,
+    );
+    let ask = |cache: &str| run(&dir, &mock, &["--model", "gpt-4o", cache, "--prompt", "Hi"]);
+    let first = ask("--cache");
+    assert_eq!(String::from_utf8_lossy(&first.stdout), "First\n");
+    assert!(!String::from_utf8_lossy(&first.stderr).contains("(cached)"));
+
+    // The fixture is used up, so only the cache can answer "First"
+    let second = ask("--cache");
+    assert_eq!(String::from_utf8_lossy(&second.stdout), "First\n");
+    assert!(String::from_utf8_lossy(&second.stderr).contains("(cached)"));
+
+    let uncached = ask("--no-cache");
+    assert_eq!(
+        String::from_utf8_lossy(&uncached.stdout),
+        "This is a mock answer.\n"
+    );
+    let log = fs::read_to_string(dir.join("session.jsonl")).unwrap();
+    let cached: Vec<bool> = log
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["cached"] == true)
+        .collect();
+    assert_eq!(cached, [false, true, false]);
+
+    // `> retry` asks the API, not the cache
+    fs::write(dir.join("script.txt"), "Hi\n> retry\n").unwrap();
+    for _ in 0..2 {
+        let output = run(
+            &dir,
+            &mock,
+            &["--model", "gpt-4o", "--cache", "--script", "script.txt"],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+    let log = fs::read_to_string(dir.join("session.jsonl")).unwrap();
+    let cached: Vec<bool> = log
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "turn")
+        .map(|e| e["cached"] == true)
+        .collect();
+    assert_eq!(cached[cached.len() - 2..], [true, false]);
+
+    // Answers that do not match the schema are not cached
+    fs::write(
+        dir.join("schema.json"),
+        r#"{"name": "n", "schema": {"type": "object"}}"#,
+    )
+    .unwrap();
+    let entries = || fs::read_dir(dir.join(".open_ai_cache")).unwrap().count();
+    let before = entries();
+    let rejected = run(
+        &dir,
+        &mock,
+        &[
+            "--model",
+            "gpt-4o",
+            "--cache",
+            "--json-schema",
+            "schema.json",
+            "--json-retries",
+            "0",
+            "--prompt",
+            "Bye",
+        ],
+    );
+    assert!(!rejected.status.success());
+    assert_eq!(entries(), before);
+}
+
+#[test]
+fn moderated_choices() {
+    let dir = scratch("moderated_choices");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions",
+             "body": {"model": "gpt-4o", "choices": [
+                 {"index": 0, "finish_reason": "stop",
+                  "message": {"role": "assistant", "content": "Dangerous"}},
+                 {"index": 1, "finish_reason": "stop",
+                  "message": {"role": "assistant", "content": "Safe"}}]}},
+            {"path": "/v1/moderations", "contains": "Dangerous",
+             "body": {"results": [{"categories": {"violence": true},
+                                   "category_scores": {"violence": 0.9}}]}},
+            {"path": "/v1/moderations",
+             "body": {"results": [{"categories": {"violence": false},
+                                   "category_scores": {"violence": 0.1}}]}}]"#,
+    );
+    fs::write(
+        dir.join("config.json"),
+        r#"{"moderation": {"answers": true}}"#,
+    )
+    .unwrap();
+    fs::write(dir.join("script.txt"), "Hi\n").unwrap();
+    let output = run(
+        &dir,
+        &mock,
+        &[
+            "--model",
+            "gpt-4o",
+            "--n",
+            "2",
+            "--cache",
+            "--script",
+            "script.txt",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{output:?}");
+    // The blocked answer is never shown, so there is nothing to choose
+    assert!(!stdout.contains("Dangerous"), "{stdout}");
+    assert!(!stdout.contains("Choose"), "{stdout}");
+    assert!(stdout.contains("Safe"), "{stdout}");
+    // And a response with a blocked answer is not cached
+    assert_eq!(
+        fs::read_dir(dir.join(".open_ai_cache"))
+            .map(|d| d.count())
+            .unwrap_or(0),
+        0
+    );
+}
+
+#[test]
+fn script() {
+    let dir = scratch("script");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "France", "answer": "Paris"},
+            {"path": "/v1/chat/completions", "contains": "Say nothing", "answer": ""}]"#,
+    );
+    let run = |script: &str| {
+        fs::write(dir.join("script.txt"), script).unwrap();
+        run(
+            &dir,
+            &mock,
+            &["--model", "gpt-4o", "--script", "script.txt"],
+        )
+    };
+    let passing = run(
+        "# Capitals\nWhat is the capital of France?\nexpect-contains \"Paris\"\n\
+         > undo\nexpect-not-contains London\n",
+    );
+    let stdout = String::from_utf8_lossy(&passing.stdout);
+    assert!(passing.status.success(), "{stdout}");
+    assert!(stdout.contains("2 expectations, 0 failed"), "{stdout}");
+
+    let failing = run("What is the capital of France?\nexpect-contains \"London\"\n");
+    let stdout = String::from_utf8_lossy(&failing.stdout);
+    assert!(!failing.status.success());
+    assert!(stdout.contains("FAILED"), "{stdout}");
+    assert!(stdout.contains("1 expectations, 1 failed"), "{stdout}");
+
+    // An empty answer fails what follows it, and the script goes on
+    let empty = run("Say nothing\nexpect-not-contains London\n> undo\n");
+    let stdout = String::from_utf8_lossy(&empty.stdout);
+    assert!(!empty.status.success());
+    assert!(stdout.contains("there was no answer"), "{stdout}");
+    assert!(stdout.contains("1 expectations, 1 failed"), "{stdout}");
+
+    // The script chooses between answers too, and fails if it cannot
+    let choices = r#"{"model": "gpt-4o", "choices": [
+        {"index": 0, "finish_reason": "stop", "message": {"role": "assistant", "content": "First"}},
+        {"index": 1, "finish_reason": "stop", "message": {"role": "assistant", "content": "Second"}}]}"#;
+    let chooser = crate::mock(
+        &dir,
+        &format!(r#"[{{"path": "/v1/chat/completions", "body": {choices}}}]"#),
+    );
+    let choose = |script: &str| {
+        fs::write(dir.join("script.txt"), script).unwrap();
+        crate::run(
+            &dir,
+            &chooser,
+            &["--model", "gpt-4o", "--n", "2", "--script", "script.txt"],
+        )
+    };
+    let chosen = choose("Which?\n2\nexpect-contains Second\n");
+    let stdout = String::from_utf8_lossy(&chosen.stdout);
+    assert!(chosen.status.success(), "{stdout}");
+    assert!(stdout.contains("1 expectations, 0 failed"), "{stdout}");
+    let unanswered = choose("Which?\n");
+    let stdout = String::from_utf8_lossy(&unanswered.stdout);
+    assert!(!unanswered.status.success());
+    assert!(stdout.contains("no answer to \"Choose 1, 2:\""), "{stdout}");
+
+    // What a script types is not added to the history
+    assert!(!dir.join("history.txt").exists());
+}
+
+#[test]
+fn failed_retry() {
+    let dir = scratch("failed_retry");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "Four", "answer": "Six"},
+            {"path": "/v1/chat/completions", "contains": "2+2", "answer": "Four", "times": 1},
+            {"path": "/v1/chat/completions", "contains": "2+2", "status": 400,
+             "body": {"error": {"message": "Bad request"}}}]"#,
+    );
+    // The answer retried is still in the conversation
+    fs::write(
+        dir.join("script.txt"),
+        "What is 2+2?\nexpect-contains Four\n> retry\nAnd 3+3?\nexpect-contains Six\n",
+    )
+    .unwrap();
+    let output = run(
+        &dir,
+        &mock,
+        &["--model", "gpt-4o", "--script", "script.txt"],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("Bad request"), "{stdout}");
+    assert!(stdout.contains("The last answer is kept"), "{stdout}");
+}
+
+#[test]
+fn json_schema_retry_and_continue() {
+    let dir = scratch("json_schema_retry_and_continue");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "does not match the JSON schema",
+             "answer": "{\"city\": \"Paris\"}"},
+            {"path": "/v1/chat/completions", "contains": "Continue from exactly",
+             "answer": "me\"}"},
+            {"path": "/v1/chat/completions", "contains": "Italy",
+             "body": {"model": "gpt-4o", "choices": [{"index": 0, "finish_reason": "length",
+                      "message": {"role": "assistant", "content": "{\"city\": \"Ro"}}]}},
+            {"path": "/v1/chat/completions", "contains": "France", "answer": "Paris"}]"#,
+    );
+    fs::write(
+        dir.join("schema.json"),
+        r#"{"name": "city", "schema": {"type": "object", "properties": {"city": {"type": "string"}},
+            "required": ["city"]}}"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("script.txt"),
+        "What is the capital of France?\nexpect-contains Paris\n\
+         What is the capital of Italy?\n> continue\nexpect-contains Rome\n",
+    )
+    .unwrap();
+    let output = run(
+        &dir,
+        &mock,
+        &[
+            "--model",
+            "gpt-4o",
+            "--json-schema",
+            "schema.json",
+            "--script",
+            "script.txt",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("asking again (1/3)"), "{stdout}");
+    assert!(stdout.contains("2 expectations, 0 failed"), "{stdout}");
+
+    // The completions end point cannot take a schema
+    let output = run(
+        &dir,
+        &mock,
+        &[
+            "--model",
+            "davinci-002",
+            "--json-schema",
+            "schema.json",
+            "--prompt",
+            "Hi",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Use a chat model"));
+}
+
+#[test]
+fn eval() {
+    let dir = scratch("eval");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "Reference answer", "answer": "9"},
+            {"path": "/v1/chat/completions", "contains": "Broken", "status": 400,
+             "body": {"error": {"message": "Bad request"}}},
+            {"path": "/v1/chat/completions", "contains": "gpt-4o-mini", "answer": "Lyon"},
+            {"path": "/v1/chat/completions", "contains": "France", "answer": "Paris"},
+            {"path": "/v1/chat/completions", "answer": "{\"sum\": 4}"}]"#,
+    );
+    fs::write(
+        dir.join("dataset.jsonl"),
+        r#"{"id": "capital", "input": "What is the capital of France?", "expected": "Paris"}
+{"id": "regex", "input": "Capital of France, in a word?", "expected": "(?i)^paris$", "scorer": "regex"}
+{"id": "sum", "input": "2+2 as JSON", "expected": {"sum": 4}, "scorer": "json"}
+{"id": "judged", "input": "Where is the Louvre, in France?", "expected": "Paris", "scorer": "judge"}
+{"id": "broken", "input": "Broken question", "expected": "Paris"}
+{"id": "bad-regex", "input": "Capital of France?", "expected": "(Paris", "scorer": "regex"}
+"#,
+    )
+    .unwrap();
+    let output = run(
+        &dir,
+        &mock,
+        &[
+            "eval",
+            "dataset.jsonl",
+            "--variant",
+            "gpt-4o",
+            "--variant",
+            "gpt-4o-mini temperature=0",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{output:?}");
+    let table = stdout.split_once("VARIANT").unwrap().1;
+    let rows: Vec<&str> = table.lines().skip(1).collect();
+    assert!(
+        rows[0].starts_with("gpt-4o ") && rows[0].contains("4/6"),
+        "{table}"
+    );
+    assert!(
+        rows[1].starts_with("gpt-4o-mini") && rows[1].contains("1/6"),
+        "{table}"
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("eval-report.json")).unwrap()).unwrap();
+    let mini = &report["variants"][1];
+    assert_eq!(mini["temperature"], 0.0);
+    assert_eq!(mini["results"][0]["answer"], "Lyon");
+    assert_eq!(mini["results"][0]["score"], 0.0);
+    assert_eq!(mini["results"][3]["score"], 0.9);
+    let broken = &report["variants"][0]["results"][4];
+    assert!(broken["ask_error"]
+        .as_str()
+        .unwrap()
+        .contains("Bad request"));
+    assert!(broken.get("score_error").is_none());
+    let bad_regex = &report["variants"][0]["results"][5];
+    assert_eq!(bad_regex["answer"], "Paris");
+    assert!(bad_regex.get("ask_error").is_none());
+    assert!(bad_regex["score_error"]
+        .as_str()
+        .unwrap()
+        .contains("(Paris"));
+}
+
+#[test]
+fn compare() {
+    let dir = scratch("compare");
+    let mock = mock(
+        &dir,
+        r#"[{"path": "/v1/chat/completions", "contains": "o3-mini", "answer": "Paris, says o3"},
+            {"path": "/v1/chat/completions", "answer": "Paris"}]"#,
+    );
+    let mut child = command(&dir, &mock, &["--model", "gpt-4o"])
+        .env("COLUMNS", "100")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"Capital of France?\n> compare gpt-4o-mini o3-mini\n2\n> undo\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let header = stdout
+        .lines()
+        .find(|l| l.starts_with("[1] gpt-4o-mini"))
+        .unwrap_or_else(|| panic!("{stdout}"));
+    assert!(header.contains("│ [2] o3-mini"), "{stdout}");
+    assert!(stdout.contains("Paris, says o3"), "{stdout}");
+
+    let log = fs::read_to_string(dir.join("session.jsonl")).unwrap();
+    let events: Vec<serde_json::Value> = log
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let compare = events.iter().find(|e| e["event"] == "compare").unwrap();
+    assert_eq!(compare["chosen"], "o3-mini");
+    assert_eq!(compare["results"][0]["answer"], "Paris");
+    assert_eq!(compare["results"][1]["usage"]["completion_tokens"], 3);
+    // The chosen answer replaced the last one
+    let undo = events.iter().find(|e| e["event"] == "undo").unwrap();
+    assert_eq!(undo["answer"], "Paris, says o3");
+}
+
+#[test]
+fn compare_subcommand() {
+    let dir = scratch("compare_subcommand");
+    let mock = mock(&dir, r#"[{"path": "/v1/completions", "answer": " Old"}]"#);
+    let output = command(
+        &dir,
+        &mock,
+        &["compare", "Hello", "gpt-4o-mini", "davinci-002"],
+    )
+    .env("COLUMNS", "40")
+    .output()
+    .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{output:?}");
+    assert!(stdout.starts_with("── [1] gpt-4o-mini ──"), "{stdout}");
+    assert!(stdout.contains("This is a mock answer."), "{stdout}");
+    assert!(stdout.contains("── [2] davinci-002 ──"), "{stdout}");
+    assert!(
+        stdout.contains("s, 10+1 tokens, $0.000022\nOld\n"),
+        "{stdout}"
+    );
+    // Only prompts are recorded
+    assert!(!dir.join("reply.txt").exists());
+    assert!(!dir.join("session.jsonl").exists());
+}
+
+#[test]
+fn api_key_sources() {
+    let dir = scratch("api_key_sources");
+    let mock = mock(&dir, "[]");
+    let run = |args: &[&str]| {
+        Command::new(BIN)
+            .args(["--model", "gpt-4o", "--base-url", &mock.url])
+            .args(args)
+            .args(["--prompt", "Hello"])
+            .current_dir(&dir)
+            .env_remove("OPENAI_API_KEY")
+            .env("HOME", &*dir)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    };
+    let missing = run(&[]);
+    assert!(!missing.status.success());
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("No API key"));
+
+    let key_file = dir.join("key");
+    fs::write(&key_file, "sk-in-a-file\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o640)).unwrap();
+        let open = run(&["--api-key-file", "key"]);
+        let stderr = String::from_utf8_lossy(&open.stderr);
+        assert!(!open.status.success());
+        assert!(stderr.contains("chmod 600") && !stderr.contains("sk-in-a-file"));
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let output = run(&["--api-key-file", "key"]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "This is a mock answer.\n"
+    );
+}
+
+#[test]
+fn profiles_and_usage() {
+    let dir = scratch("profiles_and_usage");
+    let mock = mock(&dir, "[]");
+    fs::write(
+        dir.join("config.json"),
+        r#"{"profiles": {"work": {"command": "echo sk-work", "project": "proj_work"}}}"#,
+    )
+    .unwrap();
+    let output = Command::new(BIN)
+        .args(["--profile", "work", "--model", "gpt-4o-mini"])
+        .args(["--base-url", &mock.url, "--prompt", "Hello"])
+        .current_dir(&dir)
+        .env_remove("OPENAI_API_KEY")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let ledger = fs::read_to_string(dir.join("usage.jsonl")).unwrap();
+    assert!(ledger.contains(r#""profile":"work""#), "{ledger}");
+    assert!(ledger.contains(r#""project":"proj_work""#), "{ledger}");
+    assert!(!ledger.contains("sk-work"));
+
+    let output = Command::new(BIN)
+        .arg("usage")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let table = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        table
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("work         proj_work"),
+        "{table}"
+    );
+    // `> key default` goes back to the key the session started with
+    fs::write(dir.join("script.txt"), "> key work\n> key default\nHello\n").unwrap();
+    let args = ["--model", "gpt-4o-mini", "--script", "script.txt"];
+    let output = run(
+        &dir,
+        &mock,
+        &[&args[..], &["--ledger", "other.jsonl"]].concat(),
+    );
+    assert!(output.status.success(), "{output:?}");
+    let ledger = fs::read_to_string(dir.join("other.jsonl")).unwrap();
+    assert!(
+        ledger
+            .lines()
+            .last()
+            .unwrap()
+            .contains(r#""profile":"default""#),
+        "{ledger}"
+    );
+    let output = Command::new(BIN)
+        .args(["usage", "--ledger", "other.jsonl"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    let table = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        table.lines().nth(1).unwrap().starts_with("default"),
+        "{table}"
+    );
+
+    fs::write(dir.join("script.txt"), "Hello\n> usage\n").unwrap();
+    let before = fs::read_to_string(dir.join("usage.jsonl")).unwrap();
+    let output = run(&dir, &mock, &[&args[..], &["--no-ledger"]].concat());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Usage is not being recorded"), "{stdout}");
+    assert_eq!(fs::read_to_string(dir.join("usage.jsonl")).unwrap(), before);
+
+    // `default` is the key without a profile, so no profile has the name
+    fs::write(
+        dir.join("config.json"),
+        r#"{"profiles": {"default": {"command": "echo sk-work"}}}"#,
+    )
+    .unwrap();
+    let output = run(&dir, &mock, &["--prompt", "Hello"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("a profile cannot be called default"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn proxy_and_doctor() {
+    let dir = scratch("proxy_and_doctor");
+    let mock = mock(&dir, "[]");
+    let proxy = mock.url.replace("http://", "http://worik:secret@");
+    let proxy = proxy.trim_end_matches("/v1");
+    let run = |args: &[&str]| {
+        Command::new(BIN)
+            .args(["--proxy", proxy, "--base-url", "http://api.invalid/v1"])
+            .args(args)
+            .current_dir(&dir)
+            .env("OPENAI_API_KEY", "sk-test")
+            .env_remove("NO_PROXY")
+            .env_remove("no_proxy")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    };
+    let output = run(&["doctor"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{output:?}");
+    assert!(stdout.contains("as worik with a password"), "{stdout}");
+    assert!(stdout.contains("api.invalid is reached through the proxy"));
+    assert!(stdout.contains("ok   The key works"), "{stdout}");
+    assert!(!stdout.contains("secret") && !stdout.contains("sk-test"));
+
+    let output = run(&["--model", "gpt-4o", "--prompt", "Hello"]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "This is a mock answer.\n"
+    );
+
+    let output = run(&["--no-proxy", ".invalid", "doctor"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains("reached directly, as it is in no proxy"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("FAIL Could not reach api.invalid"),
+        "{stdout}"
+    );
+}